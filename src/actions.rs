@@ -1,10 +1,17 @@
 use bevy::prelude::*;
 
 use bevy_tiled_prototype::Map;
-use crate::{core::{character::{Character, CharacterState, Direction}, config::Config, dialogue::{Dialogue, DialogueEvent}, game::{DialogueSpec, Game}, input::{Action, Flag, InputActionSet}, state::TransientState}, debug::Debuggable};
+use parry2d::{bounding_volume::AABB, math::Point};
+use crate::{core::{broadphase::{CollisionFilter, CollisionWorld}, character::{Character, CharacterState, Direction}, collider::{Collider, ColliderBehavior}, config::Config, dialogue::{Dialogue, DialogueEvent}, game::{DialogueSpec, Game}, input::{Action, Flag, InputActionSet}, state::TransientState}, debug::Debuggable};
 
-use crate::motion::VELOCITY_EPSILON;
+use crate::motion::{dialogue_behavior, VELOCITY_EPSILON};
 use crate::players::Player;
+use crate::transition::ScreenTransition;
+
+// How far in front of a character it can interact with things, in world units.
+const PROBE_REACH: f32 = 32.0;
+// Gap between the top of the target and the prompt icon, in world units.
+const PROMPT_GAP: f32 = 8.0;
 
 // Something that can trigger dialogue.
 #[derive(Debug, Default)]
@@ -12,8 +19,14 @@ pub struct DialogueActor {
     // Dialogue that the actor is currently colliding with that could be
     // triggered.
     pub collider_dialogue: Option<DialogueSpec>,
+    // Object in front of the actor that has dialogue, and its dialogue.
+    pub facing: Option<Entity>,
+    pub facing_dialogue: Option<DialogueSpec>,
 }
 
+// Icon shown above whatever the parent actor can interact with.
+pub struct InteractionPrompt;
+
 pub fn handle_movement_input_system(
     input_actions: Res<InputActionSet>,
     mut transient_state: ResMut<TransientState>,
@@ -22,6 +35,7 @@ pub fn handle_movement_input_system(
     dialogue_query: Query<&Dialogue>,
     mut debuggable: Query<(&mut Visible, Option<&Handle<Map>>), With<Debuggable>>,
     config: Res<Config>,
+    transition: Res<ScreenTransition>,
 ) {
     // check for debug status flag differing from transient_state to determine when to hide/show debug stuff
     if input_actions.has_flag(Flag::Debug) != transient_state.debug_mode {
@@ -36,6 +50,15 @@ pub fn handle_movement_input_system(
         }
     }
 
+    // stand still while changing maps
+    if transition.is_active() {
+        for (mut character, _) in query.iter_mut() {
+            character.velocity = Vec2::ZERO;
+            character.set_state(CharacterState::Idle);
+        }
+        return;
+    }
+
     for dialogue in dialogue_query.iter() {
         if dialogue.in_progress() && game_state.is_in_dialogue() {
             return;
@@ -104,7 +127,11 @@ pub fn handle_dialogue_input_system(
     dialogue_actor_query: Query<&DialogueActor>,
     mut dialogue_query: Query<&mut Dialogue>,
     mut dialogue_events: EventWriter<DialogueEvent>,
+    transition: Res<ScreenTransition>,
 ) {
+    if transition.is_active() {
+        return;
+    }
     for player in query.iter_mut() {
         if let Some(entity) = game_state.current_dialogue {
             if input_actions.is_active(Action::Accept, player.id) {
@@ -114,10 +141,13 @@ pub fn handle_dialogue_input_system(
                     dialogue.advance(&mut dialogue_events);
                     continue;
                 }
-                // Trigger the dialogue that the player is colliding with.
+                // Trigger the dialogue that the player is colliding with or
+                // facing.
                 for dialogue_actor in dialogue_actor_query.iter() {
                     let mut began = false;
-                    if let Some(spec) = &dialogue_actor.collider_dialogue {
+                    let spec_option = dialogue_actor.collider_dialogue.as_ref()
+                        .or_else(|| dialogue_actor.facing_dialogue.as_ref());
+                    if let Some(spec) = spec_option {
                         for mut dialogue in dialogue_query.iter_mut() {
                             if dialogue.begin_optional(spec.node_name.as_ref(), &mut dialogue_events) {
                                 game_state.dialogue_ui = Some(spec.ui_type);
@@ -133,3 +163,119 @@ pub fn handle_dialogue_input_system(
         }
     }
 }
+
+fn has_dialogue(behavior: &ColliderBehavior) -> bool {
+    match behavior {
+        ColliderBehavior::Dialogue(spec) => !spec.auto_display,
+        ColliderBehavior::Obstruct |
+        ColliderBehavior::Collect |
+        ColliderBehavior::Load { path: _ } |
+        ColliderBehavior::Checkpoint => false,
+    }
+}
+
+fn is_obstruction(behavior: &ColliderBehavior) -> bool {
+    match behavior {
+        ColliderBehavior::Obstruct => true,
+        ColliderBehavior::Collect |
+        ColliderBehavior::Load { path: _ } |
+        ColliderBehavior::Dialogue(_) |
+        ColliderBehavior::Checkpoint => false,
+    }
+}
+
+// Find the closest object with dialogue just in front of each actor, so that
+// it can be talked to without walking into it or through a wall.
+pub fn interaction_probe_system(
+    game_state: Res<Game>,
+    collision_world: CollisionWorld,
+    mut actor_query: Query<(Entity, &Character, &mut DialogueActor)>,
+) {
+    for (entity, character, mut dialogue_actor) in actor_query.iter_mut() {
+        dialogue_actor.facing = None;
+        dialogue_actor.facing_dialogue = None;
+        let (collider, global) = match collision_world.collider(entity) {
+            Some(found) => found,
+            None => continue,
+        };
+        let aabb = collider.bounding_volume(global);
+        let center = Vec2::new(aabb.center().x, aabb.center().y);
+        let half_extents = Vec2::new(aabb.half_extents().x, aabb.half_extents().y);
+        let facing = character.direction.to_vec2();
+
+        // A box as wide as the actor that extends out from the side it faces.
+        let probe_center = center + facing * (facing.abs().dot(half_extents) + PROBE_REACH / 2.0);
+        let probe_half_extents = facing.abs() * (PROBE_REACH / 2.0)
+            + (Vec2::ONE - facing.abs()) * half_extents;
+        let probe = AABB::new(
+            Point::new(probe_center.x - probe_half_extents.x, probe_center.y - probe_half_extents.y),
+            Point::new(probe_center.x + probe_half_extents.x, probe_center.y + probe_half_extents.y),
+        );
+        let filter = CollisionFilter {
+            behavior: Some(has_dialogue),
+            exclude: Some(entity),
+            ..Default::default()
+        };
+
+        // walls between the actor and a target block talking to it
+        let obstructions = CollisionFilter {
+            behavior: Some(is_obstruction),
+            exclude: Some(entity),
+            ..Default::default()
+        };
+
+        let mut closest: Option<(f32, Entity, DialogueSpec)> = None;
+        for target in collision_world.query_aabb(&game_state.current_map, &probe, &filter) {
+            if let Some((target_collider, target_global)) = collision_world.collider(target) {
+                let target_center = target_collider.bounding_volume(target_global).center();
+                let target_center = Vec2::new(target_center.x, target_center.y);
+                let distance = center.distance(target_center);
+                let is_blocked = collision_world.raycast(
+                    &game_state.current_map, center, target_center - center, distance, &obstructions,
+                ).map_or(false, |hit| hit.entity != target);
+                if is_blocked {
+                    continue;
+                }
+                if closest.as_ref().map_or(true, |(closest_distance, _, _)| distance < *closest_distance) {
+                    if let Some(spec) = dialogue_behavior(&target_collider.behaviors) {
+                        closest = Some((distance, target, spec));
+                    }
+                }
+            }
+        }
+        if let Some((_, target, spec)) = closest {
+            dialogue_actor.facing = Some(target);
+            dialogue_actor.facing_dialogue = Some(spec);
+        }
+    }
+}
+
+// Show each actor's prompt above what it's facing.
+pub fn interaction_prompt_system(
+    game_state: Res<Game>,
+    actor_query: Query<(&DialogueActor, &GlobalTransform, &Children)>,
+    target_query: Query<(&Collider, &GlobalTransform)>,
+    mut prompt_query: Query<(&mut Transform, &mut Visible), With<InteractionPrompt>>,
+) {
+    for (dialogue_actor, actor_global, children) in actor_query.iter() {
+        let target_top = dialogue_actor.facing
+            .filter(|_| !game_state.is_in_dialogue())
+            .and_then(|target| target_query.get(target).ok())
+            .map(|(collider, global)| {
+                let aabb = collider.bounding_volume(global);
+                Vec2::new(aabb.center().x, aabb.maxs.y + PROMPT_GAP)
+            });
+        for &child in children.iter() {
+            if let Ok((mut transform, mut visible)) = prompt_query.get_mut(child) {
+                visible.is_visible = target_top.is_some();
+                if let Some(target_top) = target_top {
+                    // The prompt is a child of the actor, so undo the actor's
+                    // transform.
+                    let local = (target_top - actor_global.translation.xy()) / actor_global.scale.xy();
+                    transform.translation.x = local.x;
+                    transform.translation.y = local.y;
+                }
+            }
+        }
+    }
+}
@@ -3,6 +3,7 @@ use bevy::{
     render::{camera::{self, Camera, CameraProjection, OrthographicProjection}, render_graph},
 };
 
+use crate::core::{game::Game, map_settings::CameraMode};
 use crate::players::Player;
 
 const CAMERA_BUFFER: f32 = 1.0;
@@ -113,6 +114,7 @@ fn is_rect_completely_inside(r1: &Rect<f32>, r2: &Rect<f32>) -> bool {
 
 pub fn update_camera_system(
     windows: Res<Windows>,
+    game_state: Res<Game>,
     mut player_query: Query<(&GlobalTransform, &Player)>,
     mut camera_query: Query<(&mut Transform,
                             &GlobalTransform,
@@ -167,35 +169,59 @@ pub fn update_camera_system(
             // Note: We don't support camera rotation or scale.
             let camera_rect = viewport(&camera_global, &projection);
             // println!("camera_rect {:?}", camera_rect);
+            let mode = game_state.map_settings.as_ref().map_or(CameraMode::Follow, |settings| settings.camera);
             if num_players <= 1 {
-                // Center on the player if not in view.
-                let is_player_in_view = is_rect_completely_inside(&full_bb, &camera_rect);
-                if !is_player_in_view {
-                    // Mutate the transform, never the global transform.
-                    let mut v1 = camera_transform.translation.clone() - player_translation;
-
-                    if v1.length() > (win_width * win_width + win_height * win_height).sqrt() / 3.0 {
-                        camera_transform.translation = player_translation;
-                    } else {
-                        let mut new_cam_translation = camera_transform.translation.clone();
-                        v1.x = margin_vec.x.min(((v1.x.abs() - CAMERA_BUFFER) / win_width).abs()) * v1.x.signum() * win_width;
-                        v1.y = margin_vec.y.min(((v1.y.abs() - CAMERA_BUFFER) / win_height).abs()) * v1.y.signum() * win_height;
-                        // println!("{:?} - {:?}", v1, margin_vec);
-                        new_cam_translation = new_cam_translation - v1 * 2.0;
-                        new_cam_translation.z = camera_transform.translation.z;
-                        camera_transform.translation = new_cam_translation;
+                match mode {
+                    // The map is centered on the origin.
+                    CameraMode::Fixed => {
+                        camera_transform.translation.x = 0.0;
+                        camera_transform.translation.y = 0.0;
+                    }
+                    CameraMode::Center => {
+                        camera_transform.translation.x = player_translation.x;
+                        camera_transform.translation.y = player_translation.y;
+                    }
+                    CameraMode::Follow => {
+                        // Center on the player if not in view.
+                        let is_player_in_view = is_rect_completely_inside(&full_bb, &camera_rect);
+                        if !is_player_in_view {
+                            // Mutate the transform, never the global transform.
+                            let mut v1 = camera_transform.translation.clone() - player_translation;
+
+                            if v1.length() > (win_width * win_width + win_height * win_height).sqrt() / 3.0 {
+                                camera_transform.translation = player_translation;
+                            } else {
+                                let mut new_cam_translation = camera_transform.translation.clone();
+                                v1.x = margin_vec.x.min(((v1.x.abs() - CAMERA_BUFFER) / win_width).abs()) * v1.x.signum() * win_width;
+                                v1.y = margin_vec.y.min(((v1.y.abs() - CAMERA_BUFFER) / win_height).abs()) * v1.y.signum() * win_height;
+                                // println!("{:?} - {:?}", v1, margin_vec);
+                                new_cam_translation = new_cam_translation - v1 * 2.0;
+                                new_cam_translation.z = camera_transform.translation.z;
+                                camera_transform.translation = new_cam_translation;
+                            }
+
+
+                        }
                     }
-
-
                 }
             } else {
                 // Center on the center of the bounding box of all players.
-                let c = rect_center(&full_bb);
+                let c = match mode {
+                    CameraMode::Fixed => Vec2::ZERO,
+                    CameraMode::Center | CameraMode::Follow => rect_center(&full_bb),
+                };
                 camera_transform.translation.x = c.x;
                 camera_transform.translation.y = c.y;
 
                 // Zoom so that all players are in view.
-                let mut wh = rect_width_height(&full_bb);
+                let mut wh = match mode {
+                    // big enough to see players on either side of the middle
+                    CameraMode::Fixed => Vec2::new(
+                        2.0 * full_bb.left.abs().max(full_bb.right.abs()),
+                        2.0 * full_bb.top.abs().max(full_bb.bottom.abs()),
+                    ),
+                    CameraMode::Center | CameraMode::Follow => rect_width_height(&full_bb),
+                };
                 wh = expanded_to_aspect_ratio(&wh, aspect_ratio);
                 // Never zoom in smaller than the window.
                 if wh.x < win_width || wh.y < win_height {
@@ -1,8 +1,14 @@
+pub mod audio;
+pub mod broadphase;
 pub mod character;
 pub mod collider;
 pub mod config;
 pub mod dialogue;
 pub mod game;
 pub mod input;
+pub mod map_settings;
 pub mod menu;
+pub mod pause;
+pub mod save;
+pub mod settings;
 pub mod state;
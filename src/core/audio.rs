@@ -0,0 +1,322 @@
+use std::collections::HashMap as StdHashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+use bevy::{asset::FileAssetIo, prelude::*, utils::HashMap};
+use bevy_kira_audio::{Audio, AudioChannel, AudioPlugin};
+use serde::Deserialize;
+
+use super::{config::Config, dialogue::{Dialogue, DialogueEvent}, game::Game};
+
+// Seconds for one map's music to fade into the next.
+const CROSSFADE_TIME: f32 = 2.0;
+// Music volume while dialogue is shown, and seconds to get there.
+const DUCK_VOLUME: f32 = 0.35;
+const DUCK_TIME: f32 = 0.3;
+// Sound effects that can play at once, each with its own volume and pitch.
+const SFX_CHANNELS: usize = 6;
+
+// Add this plugin to your app instead of bevy_kira_audio's.
+#[derive(Debug, Default)]
+pub struct GameAudioPlugin;
+
+// Sounds are played in channels so that volume can be set per kind of sound.
+// Music has two channels so that one track can fade out while the next fades
+// in.  Sound effects take turns in a few channels.
+pub struct AudioChannels {
+    pub music: [AudioChannel; 2],
+    pub sfx: Vec<AudioChannel>,
+}
+
+impl Default for AudioChannels {
+    fn default() -> Self {
+        AudioChannels {
+            music: [
+                AudioChannel::new("music".to_string()),
+                AudioChannel::new("music2".to_string()),
+            ],
+            sfx: (0..SFX_CHANNELS)
+                .map(|i| AudioChannel::new(format!("sfx{}", i)))
+                .collect(),
+        }
+    }
+}
+
+// Event to play a named sound from the sound table, e.g. "footstep".  Names
+// that aren't in the table are played as files if they look like one.
+#[derive(Clone, Debug)]
+pub struct SoundEvent {
+    pub name: String,
+}
+
+impl SoundEvent {
+    pub fn new(name: impl Into<String>) -> SoundEvent {
+        SoundEvent { name: name.into() }
+    }
+}
+
+// Sounds for each name, loaded from a RON file in the assets.
+#[derive(Debug, Default)]
+pub struct SoundTable {
+    sounds: HashMap<String, SoundSpec>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct SoundSpec {
+    // One is chosen at random each time.
+    pub files: Vec<String>,
+    pub volume: f32,
+    // Fraction that volume and pitch vary by, up or down.
+    pub volume_variation: f32,
+    pub pitch_variation: f32,
+}
+
+impl Default for SoundSpec {
+    fn default() -> Self {
+        SoundSpec {
+            files: Vec::new(),
+            volume: 1.0,
+            volume_variation: 0.0,
+            pitch_variation: 0.0,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct SoundTableFile {
+    sounds: StdHashMap<String, SoundSpec>,
+}
+
+pub fn load_sound_table(path: &Path) -> Result<SoundTable> {
+    let mut asset_path = FileAssetIo::get_root_path();
+    asset_path.push("assets");
+    asset_path.push(path);
+
+    let contents = fs::read_to_string(asset_path.as_path())
+        .with_context(||
+            format!("error reading sound table: {:?}",
+                    asset_path.as_os_str())
+        )?;
+    let file: SoundTableFile = ron::de::from_str(contents.as_ref())
+        .with_context(||
+            format!("error parsing sound table: {:?}",
+                    asset_path.as_os_str())
+        )?;
+
+    Ok(SoundTable {
+        sounds: file.sounds.into_iter().collect(),
+    })
+}
+
+// Which sfx channel plays next, and randomness for variation.
+#[derive(Debug)]
+struct SoundPlayer {
+    next_channel: usize,
+    rng_state: u64,
+}
+
+impl Default for SoundPlayer {
+    fn default() -> Self {
+        let seed = SystemTime::now().duration_since(UNIX_EPOCH)
+            .map_or(0, |duration| duration.as_nanos() as u64);
+        SoundPlayer {
+            next_channel: 0,
+            // xorshift needs a non-zero state
+            rng_state: seed | 1,
+        }
+    }
+}
+
+impl SoundPlayer {
+    // Random number from -1 to 1.
+    fn next_signed(&mut self) -> f32 {
+        let mut x = self.rng_state;
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        self.rng_state = x;
+
+        (x >> 40) as f32 / (1u64 << 23) as f32 - 1.0
+    }
+}
+
+// The looping track for the current map.
+#[derive(Debug)]
+struct MusicPlayer {
+    track: Option<PathBuf>,
+    // Music channel that the track plays in.  The other one fades out.
+    active: usize,
+    // How far the crossfade has gone, from 0 to 1.
+    fade: f32,
+    // Multiplier that moves towards DUCK_VOLUME during dialogue.
+    duck: f32,
+    // Volumes last given to the music channels.
+    volumes: [f32; 2],
+}
+
+impl Default for MusicPlayer {
+    fn default() -> Self {
+        MusicPlayer {
+            track: None,
+            active: 0,
+            fade: 1.0,
+            duck: 1.0,
+            volumes: [-1.0; 2],
+        }
+    }
+}
+
+impl Plugin for GameAudioPlugin {
+    fn build(&self, app: &mut AppBuilder) {
+        app
+            .add_plugin(AudioPlugin)
+            .insert_resource(AudioChannels::default())
+            .insert_resource(MusicPlayer::default())
+            .insert_resource(SoundPlayer::default())
+            .add_event::<SoundEvent>()
+            .add_system(music_system.system())
+            .add_system(ui_sound_system.system())
+            .add_system(dialogue_sound_system.system())
+            .add_system(play_sound_system.system());
+    }
+}
+
+fn play_sound_system(
+    mut sound_events: EventReader<SoundEvent>,
+    config: Res<Config>,
+    audio: Res<Audio>,
+    channels: Res<AudioChannels>,
+    asset_server: Res<AssetServer>,
+    sound_table: Res<SoundTable>,
+    mut player: ResMut<SoundPlayer>,
+) {
+    for event in sound_events.iter() {
+        let default_spec;
+        let spec = match sound_table.sounds.get(&event.name) {
+            Some(spec) => spec,
+            None if event.name.contains('.') => {
+                default_spec = SoundSpec {
+                    files: vec![event.name.clone()],
+                    ..Default::default()
+                };
+                &default_spec
+            }
+            None => {
+                debug!("No sound for {}", event.name);
+                continue;
+            }
+        };
+        if spec.files.is_empty() {
+            continue;
+        }
+
+        let file_index = ((player.next_signed() + 1.0) / 2.0 * spec.files.len() as f32) as usize;
+        let file = &spec.files[file_index.min(spec.files.len() - 1)];
+        let volume = spec.volume * (1.0 + spec.volume_variation * player.next_signed());
+        let pitch = 1.0 + spec.pitch_variation * player.next_signed();
+
+        // volume settings are applied to each sound as it starts
+        let settings = &config.settings;
+        let channel = &channels.sfx[player.next_channel];
+        player.next_channel = (player.next_channel + 1) % channels.sfx.len();
+        audio.stop_channel(channel);
+        audio.set_volume_in_channel(settings.master_volume * settings.sfx_volume * volume.max(0.0), channel);
+        audio.set_playback_rate_in_channel(pitch.max(0.01), channel);
+        audio.play_in_channel(asset_server.load(file.as_str()), channel);
+    }
+}
+
+// Buttons in every menu.
+fn ui_sound_system(
+    interaction_query: Query<&Interaction, (Changed<Interaction>, With<Button>)>,
+    mut sound_events: EventWriter<SoundEvent>,
+) {
+    for interaction in interaction_query.iter() {
+        match interaction {
+            Interaction::Clicked => sound_events.send(SoundEvent::new("ui_click")),
+            Interaction::Hovered => sound_events.send(SoundEvent::new("ui_hover")),
+            Interaction::None => {}
+        }
+    }
+}
+
+fn dialogue_sound_system(
+    mut dialogue_events: EventReader<DialogueEvent>,
+    mut sound_events: EventWriter<SoundEvent>,
+) {
+    for event in dialogue_events.iter() {
+        let name = match event {
+            DialogueEvent::Begin(node_name) => format!("dialogue:{}", node_name),
+            DialogueEvent::Text(_) => "dialogue_text".to_string(),
+            DialogueEvent::End => "dialogue_end".to_string(),
+        };
+        sound_events.send(SoundEvent::new(name));
+    }
+}
+
+// Play the current map's music, crossfading when it changes and ducking it
+// while dialogue is shown.
+fn music_system(
+    time: Res<Time>,
+    config: Res<Config>,
+    audio: Res<Audio>,
+    channels: Res<AudioChannels>,
+    asset_server: Res<AssetServer>,
+    game_state: Option<Res<Game>>,
+    dialogue_query: Query<&Dialogue>,
+    mut music: ResMut<MusicPlayer>,
+) {
+    let track = game_state.as_ref()
+        .and_then(|game_state| game_state.map_settings.as_ref())
+        .and_then(|settings| settings.music.clone());
+    // wait for the map's settings rather than fading out while loading
+    let settings_known = game_state.as_ref().map_or(false, |game_state| {
+        game_state.map_settings.as_ref().map(|settings| &settings.map) == Some(&game_state.current_map)
+    });
+    if settings_known && track != music.track {
+        // the old track fades out from wherever it was
+        music.active = 1 - music.active;
+        music.fade = 1.0 - music.fade;
+        let channel = &channels.music[music.active];
+        audio.stop_channel(channel);
+        if let Some(path) = &track {
+            audio.play_looped_in_channel(asset_server.load(path.as_path()), channel);
+        }
+        music.track = track;
+    }
+
+    let dt = time.delta_seconds();
+    if music.fade < 1.0 {
+        music.fade = (music.fade + dt / CROSSFADE_TIME).min(1.0);
+        if music.fade >= 1.0 {
+            audio.stop_channel(&channels.music[1 - music.active]);
+        }
+    }
+
+    let in_dialogue = dialogue_query.iter().any(|dialogue| dialogue.in_progress());
+    let duck_target = if in_dialogue { DUCK_VOLUME } else { 1.0 };
+    let duck_step = (1.0 - DUCK_VOLUME) * dt / DUCK_TIME;
+    music.duck = if music.duck < duck_target {
+        (music.duck + duck_step).min(duck_target)
+    } else {
+        (music.duck - duck_step).max(duck_target)
+    };
+
+    // volume settings may change at any time
+    let settings = &config.settings;
+    let volume = settings.master_volume * settings.music_volume * music.duck;
+    let active = music.active;
+    let volumes = [
+        (active, volume * music.fade),
+        (1 - active, volume * (1.0 - music.fade)),
+    ];
+    for (index, channel_volume) in volumes.iter() {
+        if (music.volumes[*index] - channel_volume).abs() > f32::EPSILON {
+            audio.set_volume_in_channel(*channel_volume, &channels.music[*index]);
+            music.volumes[*index] = *channel_volume;
+        }
+    }
+}
@@ -0,0 +1,535 @@
+use bevy::{ecs::system::SystemParam, prelude::*, transform::TransformSystem, utils::{HashMap, HashSet}};
+use bevy_tiled_prototype::Map;
+use parry2d::{bounding_volume::{AABB, BoundingVolume}, math::{Point, Vector}, query::{PointQuery, Ray, RayCast}};
+
+use super::collider::{Collider, ColliderBehavior, CollisionLayers};
+
+// Width and height of a grid cell in world units.
+const CELL_SIZE: f32 = 256.0;
+// Colliders that would cover more cells than this, like the bounds of a whole
+// map, are kept in a list that every query checks instead.
+const MAX_CELLS: i64 = 64;
+
+type Cell = (i32, i32);
+
+#[derive(Debug, Default)]
+pub struct BroadphasePlugin;
+
+impl Plugin for BroadphasePlugin {
+    fn build(&self, app: &mut AppBuilder) {
+        app
+            .insert_resource(CollisionBroadphase::default())
+            // After transforms are propagated so that the grid has where
+            // colliders are this frame.
+            .add_system_to_stage(CoreStage::PostUpdate, update_broadphase_system.system()
+                .after(TransformSystem::TransformPropagate))
+            .add_system_to_stage(CoreStage::Last, remove_despawned_colliders_system.system());
+    }
+}
+
+// Spatial hash of colliders so that movement only checks nearby colliders.
+// There's a grid per map so that colliders in hidden maps are never checked.
+// Colliders that don't belong to a map, like characters, have their own grid
+// that's checked along with every map.
+//
+// Raycasts, point and area queries use it to find candidates, then check them
+// against their current collider and transform.
+#[derive(Debug, Default)]
+pub struct CollisionBroadphase {
+    grids: HashMap<Option<Handle<Map>>, HashMap<Cell, Vec<Entity>>>,
+    large: HashMap<Option<Handle<Map>>, Vec<Entity>>,
+    entries: HashMap<Entity, BroadphaseEntry>,
+}
+
+#[derive(Debug)]
+struct BroadphaseEntry {
+    map: Option<Handle<Map>>,
+    // None for large colliders that aren't in the grid.
+    cells: Option<(Cell, Cell)>,
+}
+
+// Queries colliders in the world as they are now.  Add this as a system
+// parameter to raycast, point-test or AABB-query colliders.
+#[derive(SystemParam)]
+pub struct CollisionWorld<'a> {
+    broadphase: Res<'a, CollisionBroadphase>,
+    colliders: Query<'a, (&'static Collider, &'static GlobalTransform)>,
+}
+
+// Looks up an entity's collider as it is now, usually with a query.
+pub trait ColliderLookup {
+    fn collider(&self, entity: Entity) -> Option<(&Collider, &GlobalTransform)>;
+}
+
+impl<'w, 'a> ColliderLookup for Query<'w, (&'a Collider, &'a GlobalTransform)> {
+    fn collider(&self, entity: Entity) -> Option<(&Collider, &GlobalTransform)> {
+        self.get(entity).ok()
+    }
+}
+
+// Which colliders a query should find.
+#[derive(Clone, Debug)]
+pub struct CollisionFilter {
+    // Only colliders on at least one of these layers.
+    pub layers: CollisionLayers,
+    // Only colliders with a behavior that matches.
+    pub behavior: Option<fn(&ColliderBehavior) -> bool>,
+    // Usually the entity doing the query.
+    pub exclude: Option<Entity>,
+}
+
+impl Default for CollisionFilter {
+    fn default() -> Self {
+        CollisionFilter {
+            layers: CollisionLayers::ALL,
+            behavior: None,
+            exclude: None,
+        }
+    }
+}
+
+impl CollisionFilter {
+    fn matches(&self, entity: Entity, collider: &Collider) -> bool {
+        self.exclude != Some(entity)
+            && collider.layers.intersects(self.layers)
+            && self.behavior.map_or(true, |matches| collider.behaviors.iter().any(matches))
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct RayHit {
+    pub entity: Entity,
+    pub distance: f32,
+    // Not used by the game yet.
+    #[allow(dead_code)]
+    pub point: Vec2,
+}
+
+impl<'a> CollisionWorld<'a> {
+    pub fn collider(&self, entity: Entity) -> Option<(&Collider, &GlobalTransform)> {
+        self.colliders.collider(entity)
+    }
+
+    // Colliders in the map whose bounding boxes overlap the AABB.
+    pub fn query_aabb(&self, map: &Handle<Map>, aabb: &AABB, filter: &CollisionFilter) -> Vec<Entity> {
+        self.broadphase.query_aabb(map, aabb, filter, &self.colliders)
+    }
+
+    // Colliders in the map that contain the point, e.g. to find free space.
+    // Not used by the game yet.
+    #[allow(dead_code)]
+    pub fn query_point(&self, map: &Handle<Map>, point: Vec2, filter: &CollisionFilter) -> Vec<Entity> {
+        self.broadphase.query_point(map, point, filter, &self.colliders)
+    }
+
+    // The closest collider in the map along a ray, up to max_distance away.
+    pub fn raycast(&self,
+        map: &Handle<Map>,
+        origin: Vec2,
+        direction: Vec2,
+        max_distance: f32,
+        filter: &CollisionFilter,
+    ) -> Option<RayHit> {
+        self.broadphase.raycast(map, origin, direction, max_distance, filter, &self.colliders)
+    }
+}
+
+impl CollisionBroadphase {
+    // Colliders that may touch the AABB, in the map or not in any map.
+    pub fn query(&self, map: &Handle<Map>, aabb: &AABB) -> Vec<Entity> {
+        let (min, max) = cell_range(aabb);
+        let mut found: HashSet<Entity> = HashSet::default();
+        for key in [Some(map.clone()), None].iter() {
+            if let Some(entities) = self.large.get(key) {
+                found.extend(entities.iter().cloned());
+            }
+            if let Some(grid) = self.grids.get(key) {
+                for x in min.0..=max.0 {
+                    for y in min.1..=max.1 {
+                        if let Some(entities) = grid.get(&(x, y)) {
+                            found.extend(entities.iter().cloned());
+                        }
+                    }
+                }
+            }
+        }
+
+        found.into_iter().collect()
+    }
+
+    // Colliders whose bounding boxes overlap the AABB.
+    pub fn query_aabb(&self,
+        map: &Handle<Map>,
+        aabb: &AABB,
+        filter: &CollisionFilter,
+        colliders: &impl ColliderLookup,
+    ) -> Vec<Entity> {
+        self.filtered(map, aabb, filter, colliders).into_iter()
+            .filter(|(_, collider, global)| collider.bounding_volume(global).intersects(aabb))
+            .map(|(entity, _, _)| entity)
+            .collect()
+    }
+
+    // Colliders that contain the point.
+    pub fn query_point(&self,
+        map: &Handle<Map>,
+        point: Vec2,
+        filter: &CollisionFilter,
+        colliders: &impl ColliderLookup,
+    ) -> Vec<Entity> {
+        let aabb = AABB::new(Point::new(point.x, point.y), Point::new(point.x, point.y));
+        self.filtered(map, &aabb, filter, colliders).into_iter()
+            .filter(|(_, collider, global)| {
+                collider.world_shape(global).as_shape()
+                    .contains_point(&collider.isometry(global, Vec2::ZERO), &aabb.mins)
+            })
+            .map(|(entity, _, _)| entity)
+            .collect()
+    }
+
+    // The closest collider along a ray, up to max_distance away.
+    pub fn raycast(&self,
+        map: &Handle<Map>,
+        origin: Vec2,
+        direction: Vec2,
+        max_distance: f32,
+        filter: &CollisionFilter,
+        colliders: &impl ColliderLookup,
+    ) -> Option<RayHit> {
+        let direction = direction.normalize_or_zero();
+        if direction == Vec2::ZERO {
+            return None;
+        }
+        let end = origin + direction * max_distance;
+        let aabb = AABB::new(
+            Point::new(origin.x.min(end.x), origin.y.min(end.y)),
+            Point::new(origin.x.max(end.x), origin.y.max(end.y)),
+        );
+        let ray = Ray::new(Point::new(origin.x, origin.y), Vector::new(direction.x, direction.y));
+
+        self.filtered(map, &aabb, filter, colliders).into_iter()
+            .filter_map(|(entity, collider, global)| {
+                let isometry = collider.isometry(global, Vec2::ZERO);
+                collider.world_shape(global).as_shape()
+                    .cast_ray(&isometry, &ray, max_distance, true)
+                    .map(|distance| RayHit {
+                        entity,
+                        distance,
+                        point: origin + direction * distance,
+                    })
+            })
+            .min_by(|a, b| a.distance.partial_cmp(&b.distance).unwrap_or(std::cmp::Ordering::Equal))
+    }
+
+    // Candidates that still exist and match the filter.
+    fn filtered<'c>(&self,
+        map: &Handle<Map>,
+        aabb: &AABB,
+        filter: &CollisionFilter,
+        colliders: &'c impl ColliderLookup,
+    ) -> Vec<(Entity, &'c Collider, &'c GlobalTransform)> {
+        self.query(map, aabb).into_iter()
+            .filter_map(|entity| colliders.collider(entity).map(|(collider, global)| (entity, collider, global)))
+            .filter(|(entity, collider, _)| filter.matches(*entity, collider))
+            .collect()
+    }
+
+    fn insert(&mut self, entity: Entity, map: Option<Handle<Map>>, collider: &Collider, transform: &GlobalTransform) {
+        self.remove(entity);
+        let (min, max) = cell_range(&collider.bounding_volume(transform));
+        let num_cells = (max.0 as i64 - min.0 as i64 + 1) * (max.1 as i64 - min.1 as i64 + 1);
+        if num_cells > MAX_CELLS {
+            self.large.entry(map.clone()).or_insert_with(Vec::new).push(entity);
+            self.entries.insert(entity, BroadphaseEntry { map, cells: None });
+            return;
+        }
+        let grid = self.grids.entry(map.clone()).or_insert_with(HashMap::default);
+        for x in min.0..=max.0 {
+            for y in min.1..=max.1 {
+                grid.entry((x, y)).or_insert_with(Vec::new).push(entity);
+            }
+        }
+        self.entries.insert(entity, BroadphaseEntry { map, cells: Some((min, max)) });
+    }
+
+    fn remove(&mut self, entity: Entity) {
+        let entry = match self.entries.remove(&entity) {
+            None => return,
+            Some(entry) => entry,
+        };
+        let (min, max) = match entry.cells {
+            Some(cells) => cells,
+            None => {
+                if let Some(entities) = self.large.get_mut(&entry.map) {
+                    entities.retain(|&e| e != entity);
+                    if entities.is_empty() {
+                        self.large.remove(&entry.map);
+                    }
+                }
+                return;
+            }
+        };
+        if let Some(grid) = self.grids.get_mut(&entry.map) {
+            for x in min.0..=max.0 {
+                for y in min.1..=max.1 {
+                    if let Some(entities) = grid.get_mut(&(x, y)) {
+                        entities.retain(|&e| e != entity);
+                        if entities.is_empty() {
+                            grid.remove(&(x, y));
+                        }
+                    }
+                }
+            }
+            if grid.is_empty() {
+                self.grids.remove(&entry.map);
+            }
+        }
+    }
+}
+
+fn cell_range(aabb: &AABB) -> (Cell, Cell) {
+    let to_cell = |x: f32, y: f32| ((x / CELL_SIZE).floor() as i32, (y / CELL_SIZE).floor() as i32);
+
+    (to_cell(aabb.mins.x, aabb.mins.y), to_cell(aabb.maxs.x, aabb.maxs.y))
+}
+
+// Only colliders that were added, changed or moved are updated.
+fn update_broadphase_system(
+    mut broadphase: ResMut<CollisionBroadphase>,
+    collider_query: Query<
+        (Entity, &Collider, &GlobalTransform, Option<&Handle<Map>>, Option<&Parent>),
+        Or<(Changed<Collider>, Changed<GlobalTransform>)>,
+    >,
+    map_query: Query<&Handle<Map>>,
+) {
+    for (entity, collider, global, map_option, parent_option) in collider_query.iter() {
+        // Objects inside tile objects belong to the map of their parent.
+        let map = map_option.cloned().or_else(||
+            parent_option.and_then(|parent| map_query.get(parent.0).ok().cloned())
+        );
+        broadphase.insert(entity, map, collider, global);
+    }
+}
+
+// Removals are only visible until the end of the frame, so this runs last to
+// see colliders despawned by any stage.
+fn remove_despawned_colliders_system(
+    mut broadphase: ResMut<CollisionBroadphase>,
+    removed_colliders: RemovedComponents<Collider>,
+) {
+    for entity in removed_colliders.iter() {
+        broadphase.remove(entity);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::collider::ColliderShape;
+
+    impl ColliderLookup for HashMap<Entity, (Collider, GlobalTransform)> {
+        fn collider(&self, entity: Entity) -> Option<(&Collider, &GlobalTransform)> {
+            self.get(&entity).map(|(collider, global)| (collider, global))
+        }
+    }
+
+    fn is_obstruction(behavior: &ColliderBehavior) -> bool {
+        *behavior == ColliderBehavior::Obstruct
+    }
+
+    // Add colliders at positions, returning their entities in order.
+    fn add_colliders(
+        broadphase: &mut CollisionBroadphase,
+        colliders: &mut HashMap<Entity, (Collider, GlobalTransform)>,
+        added: Vec<(Collider, Vec2)>,
+    ) -> Vec<Entity> {
+        added.into_iter()
+            .map(|(collider, position)| {
+                let entity = Entity::new(colliders.len() as u32);
+                let global = GlobalTransform::from_translation(position.extend(0.0));
+                broadphase.insert(entity, None, &collider, &global);
+                colliders.insert(entity, (collider, global));
+                entity
+            })
+            .collect()
+    }
+
+    fn wall() -> Collider {
+        Collider::single(ColliderBehavior::Obstruct, Vec2::new(10.0, 10.0), Vec2::ZERO)
+    }
+
+    fn gem() -> Collider {
+        Collider::single(ColliderBehavior::Collect, Vec2::new(10.0, 10.0), Vec2::ZERO)
+            .with_layers(CollisionLayers::ITEM, CollisionLayers::PLAYER)
+    }
+
+    fn sorted(mut entities: Vec<Entity>) -> Vec<Entity> {
+        entities.sort();
+        entities
+    }
+
+    #[test]
+    fn aabb_query_filters_by_layer_behavior_and_entity() {
+        let mut broadphase = CollisionBroadphase::default();
+        let mut colliders = HashMap::default();
+        let entities = add_colliders(&mut broadphase, &mut colliders, vec![
+            (wall(), Vec2::ZERO),
+            (gem(), Vec2::new(4.0, 0.0)),
+            (wall(), Vec2::new(100.0, 0.0)),
+        ]);
+        let (wall_entity, gem_entity) = (entities[0], entities[1]);
+        let map = Handle::default();
+        let aabb = AABB::new(Point::new(-1.0, -1.0), Point::new(1.0, 1.0));
+        let query = |filter: CollisionFilter| sorted(broadphase.query_aabb(&map, &aabb, &filter, &colliders));
+
+        assert_eq!(query(CollisionFilter::default()), sorted(vec![wall_entity, gem_entity]));
+        assert_eq!(query(CollisionFilter {
+            layers: CollisionLayers::ITEM,
+            ..Default::default()
+        }), vec![gem_entity]);
+        assert_eq!(query(CollisionFilter {
+            behavior: Some(is_obstruction),
+            ..Default::default()
+        }), vec![wall_entity]);
+        assert_eq!(query(CollisionFilter {
+            exclude: Some(wall_entity),
+            ..Default::default()
+        }), vec![gem_entity]);
+    }
+
+    #[test]
+    fn point_query_uses_shapes_and_skips_despawned() {
+        let mut broadphase = CollisionBroadphase::default();
+        let mut colliders = HashMap::default();
+        let behaviors = wall().behaviors;
+        let ball = Collider::with_shape(behaviors, ColliderShape::ellipse(Vec2::new(20.0, 20.0)), Vec2::ZERO);
+        let entities = add_colliders(&mut broadphase, &mut colliders, vec![
+            (ball, Vec2::ZERO),
+            (gem(), Vec2::new(30.0, 0.0)),
+        ]);
+        let map = Handle::default();
+        let filter = CollisionFilter::default();
+
+        assert_eq!(broadphase.query_point(&map, Vec2::new(9.0, 0.0), &filter, &colliders), vec![entities[0]]);
+        // In the ball's bounding box, but not in the ball.
+        assert!(broadphase.query_point(&map, Vec2::new(8.0, 8.0), &filter, &colliders).is_empty());
+        assert!(broadphase.query_point(&map, Vec2::new(30.0, 0.0), &CollisionFilter {
+            layers: CollisionLayers::DEFAULT,
+            ..Default::default()
+        }, &colliders).is_empty());
+
+        // Still in the grid, but the entity is gone.
+        colliders.remove(&entities[1]);
+        assert!(broadphase.query_point(&map, Vec2::new(30.0, 0.0), &filter, &colliders).is_empty());
+    }
+
+    #[test]
+    fn raycast_finds_closest_match() {
+        let mut broadphase = CollisionBroadphase::default();
+        let mut colliders = HashMap::default();
+        let entities = add_colliders(&mut broadphase, &mut colliders, vec![
+            (gem(), Vec2::new(25.0, 0.0)),
+            (wall(), Vec2::new(50.0, 0.0)),
+            (wall(), Vec2::new(100.0, 0.0)),
+        ]);
+        let map = Handle::default();
+        let raycast = |max_distance: f32, filter: CollisionFilter| {
+            broadphase.raycast(&map, Vec2::ZERO, Vec2::X, max_distance, &filter, &colliders)
+        };
+
+        let hit = raycast(200.0, CollisionFilter::default()).unwrap();
+        assert_eq!(hit.entity, entities[0]);
+        assert!((hit.distance - 20.0).abs() < 1e-4);
+        assert!((hit.point - Vec2::new(20.0, 0.0)).length() < 1e-4);
+
+        let obstructions = CollisionFilter {
+            behavior: Some(is_obstruction),
+            ..Default::default()
+        };
+        assert_eq!(raycast(200.0, obstructions.clone()).unwrap().entity, entities[1]);
+        assert!(raycast(40.0, obstructions).is_none());
+        assert!(raycast(200.0, CollisionFilter {
+            layers: CollisionLayers::NPC,
+            ..Default::default()
+        }).is_none());
+        assert!(broadphase.raycast(&map, Vec2::ZERO, Vec2::ZERO, 200.0, &CollisionFilter::default(), &colliders).is_none());
+    }
+
+    // Colliders that a system despawns during Update, like collected items.
+    struct Despawn;
+
+    fn despawn_system(mut commands: Commands, query: Query<Entity, With<Despawn>>) {
+        for entity in query.iter() {
+            commands.entity(entity).despawn();
+        }
+    }
+
+    fn query_at(app: &AppBuilder, point: Vec2) -> Vec<Entity> {
+        let aabb = AABB::new(Point::new(point.x - 1.0, point.y - 1.0), Point::new(point.x + 1.0, point.y + 1.0));
+        app.app.world.get_resource::<CollisionBroadphase>().unwrap()
+            .query(&Handle::default(), &aabb)
+    }
+
+    fn query_origin(app: &AppBuilder) -> Vec<Entity> {
+        query_at(app, Vec2::ZERO)
+    }
+
+    fn spawn_collider(app: &mut AppBuilder, collider: Collider) -> Entity {
+        app.app.world.spawn()
+            .insert(collider)
+            .insert(Transform::default())
+            .insert(GlobalTransform::default())
+            .id()
+    }
+
+    fn test_app() -> AppBuilder {
+        let mut app = App::build();
+        app.add_plugin(bevy::transform::TransformPlugin)
+            .add_plugin(BroadphasePlugin);
+
+        app
+    }
+
+    #[test]
+    fn colliders_despawned_during_update_are_removed() {
+        let mut app = test_app();
+        app.add_system(despawn_system.system());
+        let entity = spawn_collider(&mut app, wall());
+
+        app.app.update();
+        assert_eq!(query_origin(&app), vec![entity]);
+
+        app.app.world.entity_mut(entity).insert(Despawn);
+        app.app.update();
+        assert!(query_origin(&app).is_empty());
+    }
+
+    #[test]
+    fn colliders_are_found_where_they_moved_this_frame() {
+        let mut app = test_app();
+        let entity = spawn_collider(&mut app, wall());
+        app.app.update();
+
+        app.app.world.get_mut::<Transform>(entity).unwrap().translation = Vec3::new(1000.0, 0.0, 0.0);
+        app.app.update();
+        assert!(query_origin(&app).is_empty());
+        assert_eq!(query_at(&app, Vec2::new(1000.0, 0.0)), vec![entity]);
+    }
+
+    #[test]
+    fn large_colliders_are_always_candidates() {
+        let mut broadphase = CollisionBroadphase::default();
+        let mut colliders = HashMap::default();
+        let bounds = Collider::single(ColliderBehavior::Obstruct, Vec2::new(100000.0, 100000.0), Vec2::ZERO);
+        let entities = add_colliders(&mut broadphase, &mut colliders, vec![(bounds, Vec2::ZERO)]);
+        let map = Handle::default();
+        let far = AABB::new(Point::new(40000.0, 40000.0), Point::new(40001.0, 40001.0));
+
+        assert!(broadphase.grids.is_empty());
+        assert_eq!(broadphase.query(&map, &far), entities);
+
+        broadphase.remove(entities[0]);
+        assert!(broadphase.query(&map, &far).is_empty());
+        assert!(broadphase.large.is_empty());
+    }
+}
@@ -69,6 +69,29 @@ impl Default for Character {
     }
 }
 
+impl Direction {
+    // Parse a name like "north", e.g. from a map property.
+    pub fn from_name(name: &str) -> Option<Direction> {
+        match name.to_lowercase().as_str() {
+            "north" | "up" => Some(Direction::North),
+            "south" | "down" => Some(Direction::South),
+            "east" | "right" => Some(Direction::East),
+            "west" | "left" => Some(Direction::West),
+            _ => None,
+        }
+    }
+
+    // Unit vector pointing the way the direction faces.
+    pub fn to_vec2(self) -> Vec2 {
+        match self {
+            Direction::North => Vec2::new(0.0, 1.0),
+            Direction::South => Vec2::new(0.0, -1.0),
+            Direction::East => Vec2::new(1.0, 0.0),
+            Direction::West => Vec2::new(-1.0, 0.0),
+        }
+    }
+}
+
 impl CharacterState {
     pub fn is_stepping(&self) -> bool {
         match self {
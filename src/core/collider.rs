@@ -1,14 +1,38 @@
+use anyhow::{bail, Result};
 use bevy::prelude::*;
 use bevy::utils::HashSet;
-use parry2d::{self as parry, bounding_volume::BoundingVolume, shape::Cuboid};
+use parry2d::{self as parry, bounding_volume::BoundingVolume, shape::{Ball, ConvexPolygon, Cuboid, Polyline, Shape}};
 
 use crate::core::game::DialogueSpec;
 
+// Number of segments used to approximate an ellipse that isn't a circle.
+const ELLIPSE_SEGMENTS: usize = 16;
+
 #[derive(Debug, Clone)]
 pub struct Collider {
     pub behaviors: HashSet<ColliderBehavior>,
-    pub shape: Cuboid,
+    pub shape: ColliderShape,
     pub offset: Vec2,
+    // What this collider is.
+    pub layers: CollisionLayers,
+    // What this collider interacts with.  Both colliders must accept each
+    // other for them to collide.
+    pub mask: CollisionLayers,
+}
+
+// Bit set of collision layers.
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+pub struct CollisionLayers(pub u32);
+
+// Geometry of a collider, centered on its entity and in the entity's
+// unscaled units.
+#[derive(Debug, Clone)]
+pub enum ColliderShape {
+    Cuboid(Cuboid),
+    Ball(Ball),
+    ConvexPolygon(ConvexPolygon),
+    // Segments that only collide along their length, e.g. curved walls.
+    Polyline(Polyline),
 }
 
 #[derive(Clone, Debug, Hash, PartialEq, Eq)]
@@ -21,6 +45,43 @@ pub enum ColliderBehavior {
     Load { path: String },
     // Begin dialogue.
     Dialogue(DialogueSpec),
+    // Save the game when touched.
+    Checkpoint,
+}
+
+impl CollisionLayers {
+    pub const NONE: CollisionLayers = CollisionLayers(0);
+    pub const ALL: CollisionLayers = CollisionLayers(u32::MAX);
+    // Layer of anything that doesn't specify one, like walls.
+    pub const DEFAULT: CollisionLayers = CollisionLayers(1 << 0);
+    pub const PLAYER: CollisionLayers = CollisionLayers(1 << 1);
+    pub const NPC: CollisionLayers = CollisionLayers(1 << 2);
+    pub const PROJECTILE: CollisionLayers = CollisionLayers(1 << 3);
+    pub const ITEM: CollisionLayers = CollisionLayers(1 << 4);
+    pub const WATER: CollisionLayers = CollisionLayers(1 << 5);
+
+    // Parse comma-separated layer names, e.g. "player, npc".
+    pub fn parse(names: &str) -> Result<CollisionLayers> {
+        let mut layers = CollisionLayers::NONE;
+        for name in names.split(',').map(str::trim).filter(|name| !name.is_empty()) {
+            layers.0 |= match name {
+                "all" => CollisionLayers::ALL,
+                "default" => CollisionLayers::DEFAULT,
+                "player" => CollisionLayers::PLAYER,
+                "npc" => CollisionLayers::NPC,
+                "projectile" => CollisionLayers::PROJECTILE,
+                "item" => CollisionLayers::ITEM,
+                "water" => CollisionLayers::WATER,
+                _ => bail!("unknown collision layer: {:?}", name),
+            }.0;
+        }
+
+        Ok(layers)
+    }
+
+    pub fn intersects(self, other: CollisionLayers) -> bool {
+        self.0 & other.0 != 0
+    }
 }
 
 #[derive(Clone, Debug, Default)]
@@ -28,17 +89,120 @@ pub struct Collision {
     pub behaviors: HashSet<ColliderBehavior>,
 }
 
-impl Collider {
-    pub fn new(behaviors: HashSet<ColliderBehavior>, width_height: Vec2, offset: Vec2) -> Collider {
+impl ColliderShape {
+    pub fn rect(width_height: Vec2) -> ColliderShape {
         let half_extent = width_height / 2.0;
         let v2 = parry::math::Vector::new(half_extent.x, half_extent.y);
+
+        ColliderShape::Cuboid(Cuboid::new(v2))
+    }
+
+    // Ellipses that aren't circles are approximated with a polygon.
+    pub fn ellipse(width_height: Vec2) -> ColliderShape {
+        let radii = width_height / 2.0;
+        if (radii.x - radii.y).abs() <= f32::EPSILON {
+            return ColliderShape::Ball(Ball::new(radii.x));
+        }
+        let points: Vec<Vec2> = (0..ELLIPSE_SEGMENTS)
+            .map(|i| {
+                let angle = i as f32 * std::f32::consts::TAU / ELLIPSE_SEGMENTS as f32;
+                Vec2::new(radii.x * angle.cos(), radii.y * angle.sin())
+            })
+            .collect();
+
+        Self::convex_polygon(&points).unwrap_or_else(|| Self::rect(width_height))
+    }
+
+    // Concave polygons use their convex hull.  Returns None when the points
+    // don't enclose an area.
+    pub fn convex_polygon(points: &[Vec2]) -> Option<ColliderShape> {
+        let points: Vec<parry::math::Point<f32>> = points.iter()
+            .map(|p| parry::math::Point::new(p.x, p.y))
+            .collect();
+
+        ConvexPolygon::from_convex_hull(&points).map(ColliderShape::ConvexPolygon)
+    }
+
+    pub fn polyline(points: &[Vec2]) -> ColliderShape {
+        let points = points.iter()
+            .map(|p| parry::math::Point::new(p.x, p.y))
+            .collect();
+
+        ColliderShape::Polyline(Polyline::new(points, None))
+    }
+
+    // Scaling a ball unevenly turns it into an ellipse, which is approximated.
+    pub fn scaled(&self, scale: Vec2) -> ColliderShape {
+        let scale_point = |p: &parry::math::Point<f32>| Vec2::new(p.x * scale.x, p.y * scale.y);
+        match self {
+            ColliderShape::Cuboid(cuboid) => {
+                let half_extents = cuboid.half_extents.component_mul(&parry::math::Vector::new(scale.x, scale.y));
+                ColliderShape::Cuboid(Cuboid::new(half_extents.abs()))
+            }
+            ColliderShape::Ball(ball) => {
+                Self::ellipse(2.0 * ball.radius * scale.abs())
+            }
+            ColliderShape::ConvexPolygon(polygon) => {
+                let points: Vec<Vec2> = polygon.points().iter().map(scale_point).collect();
+                // Mirroring reverses the winding, so rebuild the hull.
+                Self::convex_polygon(&points).unwrap_or_else(|| self.clone())
+            }
+            ColliderShape::Polyline(polyline) => {
+                let points = polyline.vertices().iter()
+                    .map(|p| parry::math::Point::new(p.x * scale.x, p.y * scale.y))
+                    .collect();
+                ColliderShape::Polyline(Polyline::new(points, Some(polyline.indices().to_vec())))
+            }
+        }
+    }
+
+    // Check whether any of the shape's parts satisfies the predicate.  Parry
+    // can't test polylines against most shapes, so they're tested a segment
+    // at a time.
+    pub fn any_part(&self, mut predicate: impl FnMut(&dyn Shape) -> bool) -> bool {
+        match self {
+            ColliderShape::Polyline(polyline) => polyline.segments().any(|segment| predicate(&segment)),
+            ColliderShape::Cuboid(_) |
+            ColliderShape::Ball(_) |
+            ColliderShape::ConvexPolygon(_) => predicate(self.as_shape()),
+        }
+    }
+
+    pub fn as_shape(&self) -> &dyn Shape {
+        match self {
+            ColliderShape::Cuboid(cuboid) => cuboid,
+            ColliderShape::Ball(ball) => ball,
+            ColliderShape::ConvexPolygon(polygon) => polygon,
+            ColliderShape::Polyline(polyline) => polyline,
+        }
+    }
+}
+
+impl Collider {
+    pub fn new(behaviors: HashSet<ColliderBehavior>, width_height: Vec2, offset: Vec2) -> Collider {
+        Self::with_shape(behaviors, ColliderShape::rect(width_height), offset)
+    }
+
+    pub fn with_shape(behaviors: HashSet<ColliderBehavior>, shape: ColliderShape, offset: Vec2) -> Collider {
         Collider {
             behaviors,
-            shape: Cuboid::new(v2),
+            shape,
             offset,
+            layers: CollisionLayers::DEFAULT,
+            mask: CollisionLayers::ALL,
         }
     }
 
+    pub fn with_layers(mut self, layers: CollisionLayers, mask: CollisionLayers) -> Collider {
+        self.layers = layers;
+        self.mask = mask;
+        self
+    }
+
+    pub fn interacts_with(&self, other: &Collider) -> bool {
+        self.layers.intersects(other.mask) && other.layers.intersects(self.mask)
+    }
+
     pub fn single(behavior: ColliderBehavior, width_height: Vec2, offset: Vec2) -> Collider {
         let mut behaviors: HashSet<ColliderBehavior> = HashSet::default();
         behaviors.insert(behavior);
@@ -62,23 +226,65 @@ impl Collider {
         global_trans: &GlobalTransform,
         delta: Vec2,
     ) -> parry::bounding_volume::AABB {
+        self.world_shape(global_trans).as_shape().compute_aabb(&self.isometry(global_trans, delta))
+    }
 
-        // TODO: Handle scale and rotation.
-        let isometry = parry::math::Isometry::translation(
-            global_trans.translation.x + delta.x + self.offset.x,
-            global_trans.translation.y + delta.y + self.offset.y);
+    // The offset from the entity's origin after rotation and scale.
+    pub fn world_offset(&self, global_trans: &GlobalTransform) -> Vec2 {
+        let offset = global_trans.scale.xy() * self.offset;
 
-        self.shape.aabb(&isometry)
+        (global_trans.rotation * offset.extend(0.0)).xy()
     }
 
-    pub fn intersect(&self, global_transform: &GlobalTransform, other: &parry::bounding_volume::AABB) -> Option<Collision> {
-        if self.behaviors.is_empty() {
+    // Parry shapes can't be scaled by an isometry, so scale the shape itself.
+    pub fn world_shape(&self, global_trans: &GlobalTransform) -> ColliderShape {
+        let scale = global_trans.scale.xy();
+        if scale == Vec2::ONE {
+            self.shape.clone()
+        } else {
+            self.shape.scaled(scale)
+        }
+    }
+
+    pub fn isometry(&self, global_trans: &GlobalTransform, delta: Vec2) -> parry::math::Isometry<f32> {
+        let translation = global_trans.translation.xy() + delta + self.world_offset(global_trans);
+        // Only rotation around Z matters in 2D.
+        let x_axis = global_trans.rotation * Vec3::X;
+        let angle = x_axis.y.atan2(x_axis.x);
+
+        parry::math::Isometry::new(parry::math::Vector::new(translation.x, translation.y), angle)
+    }
+
+    // Check whether the other collider, moved by other_delta, touches this one.
+    pub fn intersect(&self,
+        global_transform: &GlobalTransform,
+        other: &Collider,
+        other_global: &GlobalTransform,
+        other_delta: Vec2,
+    ) -> Option<Collision> {
+        if self.behaviors.is_empty() || !self.interacts_with(other) {
             return None;
         }
 
+        // Bounding boxes are cheap to check, so rule most things out first.
         let aabb = self.bounding_volume(global_transform);
+        let other_aabb = other.bounding_volume_with_translation(other_global, other_delta);
+        if !aabb.intersects(&other_aabb) {
+            return None;
+        }
 
-        if !aabb.intersects(other) {
+        let isometry = self.isometry(global_transform, Vec2::ZERO);
+        let other_isometry = other.isometry(other_global, other_delta);
+        let other_shape = other.world_shape(other_global);
+        let is_intersecting = self.world_shape(global_transform).any_part(|part| {
+            other_shape.any_part(|other_part| {
+                parry::query::intersection_test(&isometry, part, &other_isometry, other_part)
+                    // Pairs parry can't test fall back to their bounding
+                    // boxes.
+                    .unwrap_or(true)
+            })
+        });
+        if !is_intersecting {
             return None;
         }
 
@@ -86,6 +292,36 @@ impl Collider {
             behaviors: self.behaviors.clone(),
         })
     }
+
+    // Check whether the other collider, moved by other_delta, is within a
+    // distance of this one, e.g. resting against it.
+    pub fn is_near(&self,
+        global_transform: &GlobalTransform,
+        other: &Collider,
+        other_global: &GlobalTransform,
+        other_delta: Vec2,
+        distance: f32,
+    ) -> bool {
+        if self.behaviors.is_empty() || !self.interacts_with(other) {
+            return false;
+        }
+
+        let aabb = self.bounding_volume(global_transform).loosened(distance);
+        let other_aabb = other.bounding_volume_with_translation(other_global, other_delta);
+        if !aabb.intersects(&other_aabb) {
+            return false;
+        }
+
+        let isometry = self.isometry(global_transform, Vec2::ZERO);
+        let other_isometry = other.isometry(other_global, other_delta);
+        let other_shape = other.world_shape(other_global);
+        self.world_shape(global_transform).any_part(|part| {
+            other_shape.any_part(|other_part| {
+                parry::query::distance(&isometry, part, &other_isometry, other_part)
+                    .map_or(true, |actual| actual <= distance)
+            })
+        })
+    }
 }
 
 impl Collision {
@@ -105,10 +341,131 @@ impl Collision {
                 ColliderBehavior::Obstruct => return true,
                 ColliderBehavior::Collect |
                 ColliderBehavior::Load { path: _ } |
-                ColliderBehavior::Dialogue(_) => {}
+                ColliderBehavior::Dialogue(_) |
+                ColliderBehavior::Checkpoint => {}
             }
         }
 
         false
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::f32::consts::{FRAC_PI_2, FRAC_PI_4};
+
+    use super::*;
+
+    fn transform(translation: Vec2, angle: f32, scale: f32) -> GlobalTransform {
+        GlobalTransform {
+            translation: translation.extend(0.0),
+            rotation: Quat::from_rotation_z(angle),
+            scale: Vec3::new(scale, scale, 1.0),
+        }
+    }
+
+    fn assert_near(actual: f32, expected: f32) {
+        assert!((actual - expected).abs() < 1e-4, "expected {}, got {}", expected, actual);
+    }
+
+    #[test]
+    fn rotated_rect_bounding_volume() {
+        let collider = Collider::single(ColliderBehavior::Obstruct, Vec2::new(20.0, 10.0), Vec2::ZERO);
+        let aabb = collider.bounding_volume(&transform(Vec2::new(100.0, 50.0), FRAC_PI_2, 1.0));
+
+        assert_near(aabb.mins.x, 95.0);
+        assert_near(aabb.maxs.x, 105.0);
+        assert_near(aabb.mins.y, 40.0);
+        assert_near(aabb.maxs.y, 60.0);
+    }
+
+    #[test]
+    fn scaled_rotated_rect_bounding_volume() {
+        let collider = Collider::single(ColliderBehavior::Obstruct, Vec2::new(20.0, 10.0), Vec2::ZERO);
+        let aabb = collider.bounding_volume(&transform(Vec2::ZERO, FRAC_PI_2, 2.0));
+
+        assert_near(aabb.mins.x, -10.0);
+        assert_near(aabb.maxs.x, 10.0);
+        assert_near(aabb.mins.y, -20.0);
+        assert_near(aabb.maxs.y, 20.0);
+    }
+
+    #[test]
+    fn offset_follows_rotation() {
+        let collider = Collider::single(ColliderBehavior::Obstruct, Vec2::new(2.0, 2.0), Vec2::new(10.0, 0.0));
+        let offset = collider.world_offset(&transform(Vec2::ZERO, FRAC_PI_2, 3.0));
+
+        assert_near(offset.x, 0.0);
+        assert_near(offset.y, 30.0);
+    }
+
+    #[test]
+    fn layers_filter_collisions() {
+        let stop = Collider::single(ColliderBehavior::Obstruct, Vec2::new(10.0, 10.0), Vec2::ZERO)
+            .with_layers(CollisionLayers::DEFAULT, CollisionLayers::parse("player").unwrap());
+        let player = Collider::single(ColliderBehavior::Obstruct, Vec2::new(10.0, 10.0), Vec2::ZERO)
+            .with_layers(CollisionLayers::PLAYER, CollisionLayers::ALL);
+        let npc = Collider::single(ColliderBehavior::Obstruct, Vec2::new(10.0, 10.0), Vec2::ZERO)
+            .with_layers(CollisionLayers::NPC, CollisionLayers::ALL);
+        let origin = transform(Vec2::ZERO, 0.0, 1.0);
+
+        assert!(stop.intersect(&origin, &player, &origin, Vec2::ZERO).is_some());
+        assert!(stop.intersect(&origin, &npc, &origin, Vec2::ZERO).is_none());
+        assert!(CollisionLayers::parse("player, lava").is_err());
+    }
+
+    #[test]
+    fn rotated_rect_intersection() {
+        // A long thin wall rotated to run diagonally.
+        let wall = Collider::single(ColliderBehavior::Obstruct, Vec2::new(100.0, 4.0), Vec2::ZERO);
+        let wall_transform = transform(Vec2::ZERO, FRAC_PI_4, 1.0);
+        let probe = Collider::single(ColliderBehavior::Obstruct, Vec2::new(4.0, 4.0), Vec2::ZERO);
+
+        // On the diagonal.
+        let on_wall = transform(Vec2::new(20.0, 20.0), 0.0, 1.0);
+        assert!(wall.intersect(&wall_transform, &probe, &on_wall, Vec2::ZERO).is_some());
+
+        // Inside the wall's bounding box, but off the diagonal.
+        let off_wall = transform(Vec2::new(30.0, -30.0), 0.0, 1.0);
+        assert!(wall.bounding_volume(&wall_transform)
+            .intersects(&probe.bounding_volume(&off_wall)));
+        assert!(wall.intersect(&wall_transform, &probe, &off_wall, Vec2::ZERO).is_none());
+
+        // Moving onto the diagonal.
+        assert!(wall.intersect(&wall_transform, &probe, &off_wall, Vec2::new(0.0, 60.0)).is_some());
+    }
+
+    #[test]
+    fn diagonal_polyline_collides_along_its_segments() {
+        // An L-shaped wall whose corner is at the origin, drawn as a polyline.
+        let points = [Vec2::new(0.0, 100.0), Vec2::ZERO, Vec2::new(100.0, 100.0)];
+        let mut behaviors = HashSet::default();
+        behaviors.insert(ColliderBehavior::Obstruct);
+        let wall = Collider::with_shape(behaviors, ColliderShape::polyline(&points), Vec2::ZERO);
+        let origin = transform(Vec2::ZERO, 0.0, 1.0);
+        let probe = Collider::single(ColliderBehavior::Obstruct, Vec2::new(4.0, 4.0), Vec2::ZERO);
+
+        // On the diagonal segment.
+        let on_wall = transform(Vec2::new(50.0, 50.0), 0.0, 1.0);
+        assert!(wall.intersect(&origin, &probe, &on_wall, Vec2::ZERO).is_some());
+
+        // Inside the bounding box, but away from both segments.
+        let off_wall = transform(Vec2::new(70.0, 30.0), 0.0, 1.0);
+        assert!(wall.bounding_volume(&origin)
+            .intersects(&probe.bounding_volume(&off_wall)));
+        assert!(wall.intersect(&origin, &probe, &off_wall, Vec2::ZERO).is_none());
+        assert!(!wall.is_near(&origin, &probe, &off_wall, Vec2::ZERO, 1.0));
+    }
+
+    #[test]
+    fn resting_against_is_near() {
+        let wall = Collider::single(ColliderBehavior::Obstruct, Vec2::new(10.0, 10.0), Vec2::ZERO);
+        let origin = transform(Vec2::ZERO, 0.0, 1.0);
+        let probe = Collider::single(ColliderBehavior::Obstruct, Vec2::new(4.0, 4.0), Vec2::ZERO);
+        let beside = transform(Vec2::new(8.0, 0.0), 0.0, 1.0);
+
+        assert!(wall.intersect(&origin, &probe, &beside, Vec2::ZERO).is_none());
+        assert!(wall.is_near(&origin, &probe, &beside, Vec2::ZERO, 1.5));
+        assert!(!wall.is_near(&origin, &probe, &beside, Vec2::new(1.0, 0.0), 1.5));
+    }
+}
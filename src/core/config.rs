@@ -1,9 +1,16 @@
+use std::env;
 use std::fs;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 use anyhow::{Context, Result};
 use bevy::asset::FileAssetIo;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+
+use super::settings::Settings;
+
+// Directory name for per-user files like settings.
+const USER_DIR_NAME: &str = "twodina";
+const USER_SETTINGS_FILE: &str = "settings.toml";
 
 #[derive(Debug, Deserialize)]
 pub struct Config {
@@ -12,6 +19,9 @@ pub struct Config {
     pub start_map: PathBuf,
     pub map_scale: f32,
     pub start_dialogue: PathBuf,
+    // Music for maps that don't have a music property.
+    #[serde(default)]
+    pub music: Option<PathBuf>,
 
     pub char_template: String,
     pub char_height: f32,
@@ -19,6 +29,74 @@ pub struct Config {
     pub walk_speed: f32,
     pub run_speed: f32,
 
+    // Maps kept spawned, including the current one.  Others are despawned
+    // and spawned again when they're visited.
+    #[serde(default = "default_max_loaded_maps")]
+    pub max_loaded_maps: usize,
+
+    // RON file describing kinds of map objects.
+    #[serde(default = "default_object_types")]
+    pub object_types: PathBuf,
+
+    // RON file mapping sound event names to sounds.
+    #[serde(default = "default_sounds")]
+    pub sounds: PathBuf,
+
+    // Languages that dialogue is translated to.  The first is the language
+    // the assets are written in.
+    #[serde(default = "default_languages")]
+    pub languages: Vec<String>,
+
+    // User preferences.  Defaults can be set in the asset config, and the
+    // user's settings file is merged over them.
+    #[serde(default)]
+    pub settings: Settings,
+}
+
+// The part of the config that gets written to the user's settings file.
+#[derive(Debug, Serialize)]
+struct UserConfig<'a> {
+    settings: &'a Settings,
+}
+
+fn default_max_loaded_maps() -> usize {
+    3
+}
+
+fn default_object_types() -> PathBuf {
+    PathBuf::from("objects.ron")
+}
+
+fn default_sounds() -> PathBuf {
+    PathBuf::from("sounds.ron")
+}
+
+fn default_languages() -> Vec<String> {
+    vec!["en".to_string()]
+}
+
+impl Config {
+    // Path to an asset translated to the current language.  The default
+    // language uses the path as is, and others insert the language before
+    // the extension, like "dialogue/level1.fr.dialogue".
+    pub fn localized_path(&self, path: &Path) -> PathBuf {
+        let is_default = self.languages.first()
+            .map_or(true, |lang| *lang == self.settings.language);
+        if is_default {
+            return path.to_path_buf();
+        }
+        match (path.file_stem(), path.extension()) {
+            (Some(stem), Some(extension)) => {
+                let mut file_name = stem.to_os_string();
+                file_name.push(".");
+                file_name.push(&self.settings.language);
+                file_name.push(".");
+                file_name.push(extension);
+                path.with_file_name(file_name)
+            }
+            _ => path.to_path_buf(),
+        }
+    }
 }
 
 pub fn load_asset_config(name: &str) -> Result<Config> {
@@ -31,7 +109,30 @@ pub fn load_asset_config(name: &str) -> Result<Config> {
             format!("error reading config file: {:?}",
                     asset_path.as_os_str())
         )?;
-    let config = toml::from_str(contents.as_ref())
+    let mut value: toml::Value = toml::from_str(contents.as_ref())
+        .with_context(||
+            format!("error parsing config file to expected TOML format: {:?}",
+                    asset_path.as_os_str())
+        )?;
+
+    // Merge the user's settings over the asset config.
+    if let Some(user_path) = user_settings_path() {
+        if user_path.exists() {
+            let user_contents = fs::read_to_string(user_path.as_path())
+                .with_context(||
+                    format!("error reading user settings file: {:?}",
+                            user_path.as_os_str())
+                )?;
+            let user_value: toml::Value = toml::from_str(user_contents.as_ref())
+                .with_context(||
+                    format!("error parsing user settings file to expected TOML format: {:?}",
+                            user_path.as_os_str())
+                )?;
+            merge_toml(&mut value, user_value);
+        }
+    }
+
+    let config = value.try_into()
         .with_context(||
             format!("error parsing config file to expected TOML format: {:?}",
                     asset_path.as_os_str())
@@ -39,3 +140,95 @@ pub fn load_asset_config(name: &str) -> Result<Config> {
 
     Ok(config)
 }
+
+// Write the user's settings so that they're loaded on the next start.
+pub fn save_user_settings(settings: &Settings) -> Result<()> {
+    let user_path = user_settings_path()
+        .context("couldn't find a directory for user settings")?;
+    if let Some(dir) = user_path.parent() {
+        fs::create_dir_all(dir)
+            .with_context(||
+                format!("error creating user settings directory: {:?}",
+                        dir.as_os_str())
+            )?;
+    }
+    let contents = toml::to_string(&UserConfig { settings })
+        .context("error serializing user settings")?;
+    fs::write(user_path.as_path(), contents)
+        .with_context(||
+            format!("error writing user settings file: {:?}",
+                    user_path.as_os_str())
+        )?;
+
+    Ok(())
+}
+
+// Per-user directory for files that shouldn't be in the assets.
+pub fn user_dir() -> Option<PathBuf> {
+    let base = env::var_os("XDG_CONFIG_HOME").map(PathBuf::from)
+        .or_else(|| env::var_os("APPDATA").map(PathBuf::from))
+        .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".config")))?;
+
+    Some(base.join(USER_DIR_NAME))
+}
+
+fn user_settings_path() -> Option<PathBuf> {
+    user_dir().map(|dir| dir.join(USER_SETTINGS_FILE))
+}
+
+// Recursively merge tables, with values from overlay replacing base.
+fn merge_toml(base: &mut toml::Value, overlay: toml::Value) {
+    match (base, overlay) {
+        (toml::Value::Table(base_table), toml::Value::Table(overlay_table)) => {
+            for (key, overlay_value) in overlay_table {
+                match base_table.get_mut(&key) {
+                    Some(base_value) => merge_toml(base_value, overlay_value),
+                    None => {
+                        base_table.insert(key, overlay_value);
+                    }
+                }
+            }
+        }
+        (base, overlay) => *base = overlay,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn merge_toml_overlays_nested_tables() {
+        let mut base: toml::Value = toml::from_str(r#"
+            title = "Twodina"
+            [settings]
+            music_volume = 0.7
+            text_speed = 40.0
+        "#).unwrap();
+        let overlay: toml::Value = toml::from_str(r#"
+            [settings]
+            music_volume = 0.2
+            language = "fr"
+        "#).unwrap();
+
+        merge_toml(&mut base, overlay);
+        let expected: toml::Value = toml::from_str(r#"
+            title = "Twodina"
+            [settings]
+            music_volume = 0.2
+            text_speed = 40.0
+            language = "fr"
+        "#).unwrap();
+        assert_eq!(base, expected);
+    }
+
+    #[test]
+    fn merge_toml_replaces_values_of_other_types() {
+        let mut base: toml::Value = toml::from_str("languages = [\"en\", \"fr\"]\n[settings]\nlanguage = \"en\"").unwrap();
+        let overlay: toml::Value = toml::from_str("languages = [\"de\"]\nsettings = 3").unwrap();
+
+        merge_toml(&mut base, overlay);
+        let expected: toml::Value = toml::from_str("languages = [\"de\"]\nsettings = 3").unwrap();
+        assert_eq!(base, expected);
+    }
+}
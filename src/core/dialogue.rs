@@ -3,7 +3,7 @@ use bevy::{
     asset::{AssetLoader, LoadContext, LoadedAsset},
     prelude::*,
     reflect::TypeUuid,
-    utils::{BoxedFuture, HashMap},
+    utils::{BoxedFuture, HashMap, HashSet},
 };
 
 #[derive(Default)]
@@ -25,6 +25,7 @@ pub struct DialoguePlaceholder {
     pub next_index: Option<usize>,
     pub next_node_name: Option<String>,
     pub is_end: bool,
+    pub flags: HashSet<String>,
 }
 
 // A component that you should insert later once the asset loads.
@@ -36,11 +37,15 @@ pub struct Dialogue {
     pub next_index: Option<usize>,
     pub next_node_name: Option<String>,
     pub is_end: bool,
+    // Names of nodes that have been reached.
+    pub flags: HashSet<String>,
 }
 
 // Event fired by this module so that the app can handle dialogue changes.
 #[derive(Debug)]
 pub enum DialogueEvent {
+    // A named node was started, e.g. with begin().
+    Begin(String),
     End,
     Text(String),
 }
@@ -116,6 +121,7 @@ impl Default for DialoguePlaceholder {
             next_index: None,
             next_node_name: None,
             is_end: true,
+            flags: HashSet::default(),
         }
     }
 }
@@ -147,9 +153,21 @@ impl Dialogue {
             next_index: placeholder.next_index,
             next_node_name: placeholder.next_node_name.clone(),
             is_end: placeholder.is_end,
+            flags: placeholder.flags.clone(),
         }
     }
 
+    // Switch to another dialogue file, ending any dialogue in progress.  Flags
+    // are kept.
+    pub fn replace_asset(&mut self, handle: Handle<DialogueAsset>, asset: DialogueAsset) {
+        self.handle = handle;
+        self.asset = asset;
+        self.current_index = 0;
+        self.next_index = None;
+        self.next_node_name = None;
+        self.is_end = true;
+    }
+
     pub fn in_progress(&self) -> bool {
         !self.is_end
     }
@@ -217,6 +235,8 @@ impl Dialogue {
                 Some(index) => {
                     self.current_index = *index;
                     self.next_index = None;
+                    self.flags.insert(node_name.clone());
+                    dialogue_events.send(DialogueEvent::Begin(node_name.clone()));
                 }
             }
         }
@@ -243,6 +263,7 @@ impl Dialogue {
                             Some(index) => {
                                 println!("Going to: {} {}", index, name);
                                 self.current_index = *index;
+                                self.flags.insert(name.clone());
                                 continue;
                             }
                         }
@@ -1,5 +1,10 @@
+use std::collections::VecDeque;
+
 use bevy::{prelude::*, utils::{HashMap, HashSet}};
 use bevy_tiled_prototype::Map;
+use serde::{Deserialize, Serialize};
+
+use super::{dialogue::DialogueAsset, map_settings::MapSettings};
 
 // Game state that shouldn't be saved.
 #[derive(Clone, Debug)]
@@ -12,13 +17,70 @@ pub struct Game {
     // potentially persisted:
     pub current_dialogue: Option<Entity>, //temporary pub -- move to DialogueState?
     pub current_map: Handle<Map>,
-    pub entity_visibility: HashMap<Entity, bool>, // this is a minor memory leak until maps aren't recreated
+    pub object_visibility: HashMap<ObjectId, bool>,
 
-    pub next_map: Option<Handle<Map>>,
+    // Maps that portals in the current map lead to, loading in the background
+    // so that crossing is quick.  Keyed by asset path.
+    pub next_maps: HashMap<String, Handle<Map>>,
+    // The map whose portals next_maps is for.
+    pub next_maps_from: Option<Handle<Map>>,
     pub loaded_maps: HashSet<Handle<Map>>,
+    // Loaded maps from least to most recently visited.  Maps that fall off
+    // the front are despawned.
+    pub recent_maps: VecDeque<Handle<Map>>,
+    // Named entry object that players arrive at in the current map.  The
+    // spawn object is used when this is None.
+    pub entry: Option<String>,
+    // Properties of the current map, once it has loaded.
+    pub map_settings: Option<MapSettings>,
+    // Dialogue for the current map, which replaces the dialogue's asset when
+    // the map is shown.
+    pub map_dialogue: Option<Handle<DialogueAsset>>,
+    // Entry that players arrived at in the current map, or None for its
+    // spawn, so that they can be put back there after failing to leave it.
+    pub arrived_at: Option<String>,
+
+    // Saved to this slot.
+    pub save_slot: u32,
+    // Objects that changed during play.
+    pub collected_objects: HashSet<ObjectId>,
+    pub revealed_objects: HashSet<ObjectId>,
+}
+
+// Component that identifies a map object across map reloads and saves.
+#[derive(Clone, Debug, Hash, PartialEq, Eq, PartialOrd, Ord, Deserialize, Serialize)]
+pub struct ObjectId {
+    // Asset path of the map.
+    pub map: String,
+    // For objects embedded in a tile object, the tile object's ID.
+    pub parent: Option<u32>,
+    // ID of the object in the TMX.
+    pub id: u32,
 }
 
 impl Game {
+    // A fresh game that starts on the given map.
+    pub fn new(start_map: Handle<Map>) -> Game {
+        Game {
+            start_dialogue_shown: false,
+            dialogue_ui: None,
+            current_map: start_map,
+            current_dialogue: None,
+            next_maps: HashMap::default(),
+            next_maps_from: None,
+            loaded_maps: HashSet::default(),
+            recent_maps: VecDeque::new(),
+            entry: None,
+            arrived_at: None,
+            map_settings: None,
+            map_dialogue: None,
+            object_visibility: HashMap::default(),
+            save_slot: 0,
+            collected_objects: HashSet::default(),
+            revealed_objects: HashSet::default(),
+        }
+    }
+
     pub fn is_in_dialogue(&self) -> bool {
         match self.dialogue_ui {
             Some(DialogueUiType::MovementDisabled) => true,
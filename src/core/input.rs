@@ -2,6 +2,9 @@ use std::convert::TryFrom;
 use bevy::prelude::*;
 use bevy::utils::HashSet;
 use bevy::app::CoreStage::{First, PreUpdate};
+use serde::{Deserialize, Serialize};
+
+use super::config::Config;
 
 // Add this plugin to your app.
 #[derive(Debug, Default)]
@@ -17,7 +20,7 @@ pub struct InputActionSet {
 
 // The application actions.  Raw input like keyboard key presses are mapped to
 // these.
-#[derive(Copy, Clone, Debug, Hash, Eq, PartialEq)]
+#[derive(Copy, Clone, Debug, Hash, Eq, PartialEq, Deserialize, Serialize)]
 pub enum Action {
     Up,
     Down,
@@ -28,6 +31,62 @@ pub enum Action {
     Walk,
 
     Accept,
+
+    // Open or close the pause menu.
+    Pause,
+}
+
+impl Action {
+    // Actions that only fire on the frame the key is pressed instead of while
+    // it's held.
+    pub fn is_momentary(&self) -> bool {
+        match self {
+            Action::Accept | Action::Pause => true,
+            Action::Up | Action::Down | Action::Left | Action::Right |
+            Action::Walk => false,
+        }
+    }
+
+    // Pause is always on Escape since it's the way out of menus.
+    pub fn is_rebindable(&self) -> bool {
+        match self {
+            Action::Pause => false,
+            Action::Up | Action::Down | Action::Left | Action::Right |
+            Action::Walk | Action::Accept => true,
+        }
+    }
+}
+
+// Maps a keyboard key to an action for a player.  These are user settings.
+#[derive(Copy, Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct KeyBinding {
+    pub player: u32,
+    pub action: Action,
+    pub key: KeyCode,
+}
+
+impl KeyBinding {
+    pub fn new(player: u32, action: Action, key: KeyCode) -> KeyBinding {
+        KeyBinding { player, action, key }
+    }
+}
+
+pub fn default_key_bindings() -> Vec<KeyBinding> {
+    vec![
+        KeyBinding::new(0, Action::Up, KeyCode::W),
+        KeyBinding::new(0, Action::Left, KeyCode::A),
+        KeyBinding::new(0, Action::Down, KeyCode::S),
+        KeyBinding::new(0, Action::Right, KeyCode::D),
+        KeyBinding::new(0, Action::Walk, KeyCode::LShift),
+        KeyBinding::new(0, Action::Accept, KeyCode::Space),
+        KeyBinding::new(0, Action::Pause, KeyCode::Escape),
+
+        KeyBinding::new(1, Action::Up, KeyCode::Up),
+        KeyBinding::new(1, Action::Left, KeyCode::Left),
+        KeyBinding::new(1, Action::Down, KeyCode::Down),
+        KeyBinding::new(1, Action::Right, KeyCode::Right),
+        KeyBinding::new(1, Action::Walk, KeyCode::RShift),
+    ]
 }
 
 // inputs that toggle values on key/button press map to these
@@ -84,6 +143,22 @@ impl InputActionSet {
     pub fn is_active(&self, action: Action, player: u32) -> bool {
         self.actions.contains(&(action, player))
     }
+    // True if any player activated the action.
+    pub fn is_active_any(&self, action: Action) -> bool {
+        self.actions.iter().any(|(a, _)| *a == action)
+    }
+
+    // True if any player activated the action, which is then cleared so that
+    // systems that run later in the frame don't also handle it.  Needed when
+    // handling it changes the state, since the state's systems run again in
+    // the same frame.
+    pub fn consume_any(&mut self, action: Action) -> bool {
+        let was_active = self.is_active_any(action);
+        self.actions.retain(|(a, _)| *a != action);
+
+        was_active
+    }
+
     pub fn has_flag(&self, flag: Flag) -> bool {
         self.flags.contains(&flag)
     }
@@ -111,47 +186,28 @@ fn action_producer_system(
     button_inputs: Res<Input<GamepadButton>>,
     axes: Res<Axis<GamepadAxis>>,
     mut input_action_set: ResMut<InputActionSet>,
+    config: Res<Config>,
 ) {
     input_action_set.clear();
 
-    if keyboard_input.just_pressed(KeyCode::Space) {
-        input_action_set.activate(Action::Accept, 0);
-    }
-
     if keyboard_input.just_released(KeyCode::F3) {
         input_action_set.toggle(Flag::Debug);
     }
 
-    if keyboard_input.pressed(KeyCode::W) {
-        input_action_set.activate(Action::Up, 0);
-    }
-    if keyboard_input.pressed(KeyCode::A) {
-        input_action_set.activate(Action::Left, 0);
-    }
-    if keyboard_input.pressed(KeyCode::S) {
-        input_action_set.activate(Action::Down, 0);
-    }
-    if keyboard_input.pressed(KeyCode::D) {
-        input_action_set.activate(Action::Right, 0);
-    }
-    if keyboard_input.pressed(KeyCode::LShift) {
-        input_action_set.activate(Action::Walk, 0);
+    // Escape pauses even if the settings file lost its binding.
+    if keyboard_input.just_pressed(KeyCode::Escape) {
+        input_action_set.activate(Action::Pause, 0);
     }
 
-    if keyboard_input.pressed(KeyCode::Up) {
-        input_action_set.activate(Action::Up, 1);
-    }
-    if keyboard_input.pressed(KeyCode::Left) {
-        input_action_set.activate(Action::Left, 1);
-    }
-    if keyboard_input.pressed(KeyCode::Down) {
-        input_action_set.activate(Action::Down, 1);
-    }
-    if keyboard_input.pressed(KeyCode::Right) {
-        input_action_set.activate(Action::Right, 1);
-    }
-    if keyboard_input.pressed(KeyCode::RShift) {
-        input_action_set.activate(Action::Walk, 1);
+    for binding in config.settings.key_bindings.iter() {
+        let is_active = if binding.action.is_momentary() {
+            keyboard_input.just_pressed(binding.key)
+        } else {
+            keyboard_input.pressed(binding.key)
+        };
+        if is_active {
+            input_action_set.activate(binding.action, binding.player);
+        }
     }
 
     for (i, gamepad) in gamepad_set.gamepads.iter().cloned().enumerate() {
@@ -199,5 +255,8 @@ fn action_producer_system(
         if button_inputs.pressed(GamepadButton(gamepad, GamepadButtonType::West)) {
             input_action_set.activate(Action::Walk, player_num);
         }
+        if button_inputs.just_pressed(GamepadButton(gamepad, GamepadButtonType::Start)) {
+            input_action_set.activate(Action::Pause, player_num);
+        }
     }
 }
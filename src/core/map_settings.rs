@@ -0,0 +1,91 @@
+use std::path::PathBuf;
+
+use bevy::prelude::*;
+use bevy_tiled_prototype::{Map, PropertyValue};
+
+use super::config::Config;
+
+// How the camera moves on a map.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CameraMode {
+    // Move when players get near the edge of the view.
+    Follow,
+    // Keep the player in the middle of the view.
+    Center,
+    // Stay on the middle of the map.
+    Fixed,
+}
+
+// Settings from a map's custom properties, with defaults from the config:
+//
+//   scale       float   size of the map's tiles and objects
+//   background  color   clear color behind the map
+//   music       string  looping track, relative to assets, or the config's
+//   dialogue    string  dialogue file, relative to assets
+//   camera      string  "follow", "center" or "fixed"
+//   tint        color   multiplied with the colors of the map and characters
+#[derive(Clone, Debug)]
+pub struct MapSettings {
+    pub map: Handle<Map>,
+    pub scale: f32,
+    pub background: Color,
+    pub music: Option<PathBuf>,
+    pub dialogue: PathBuf,
+    pub camera: CameraMode,
+    pub tint: Color,
+}
+
+impl MapSettings {
+    pub fn from_map(handle: &Handle<Map>, map: &Map, config: &Config) -> MapSettings {
+        let props = &map.map.properties;
+        let string = |name: &str| match props.get(name) {
+            Some(PropertyValue::StringValue(value)) if !value.is_empty() => Some(value.clone()),
+            Some(_) => {
+                eprintln!("Warning: map property {} should be a string", name);
+                None
+            }
+            None => None,
+        };
+        let color = |name: &str| match props.get(name) {
+            Some(PropertyValue::ColorValue(argb)) => Some(color_from_argb(*argb)),
+            Some(PropertyValue::StringValue(value)) => Color::hex(value.trim_start_matches('#')).ok(),
+            Some(_) => {
+                eprintln!("Warning: map property {} should be a color", name);
+                None
+            }
+            None => None,
+        };
+
+        let scale = match props.get("scale") {
+            Some(PropertyValue::FloatValue(scale)) => *scale,
+            Some(PropertyValue::IntValue(scale)) => *scale as f32,
+            _ => config.map_scale,
+        };
+        let camera = match string("camera").as_deref() {
+            None | Some("follow") => CameraMode::Follow,
+            Some("center") => CameraMode::Center,
+            Some("fixed") => CameraMode::Fixed,
+            Some(other) => {
+                eprintln!("Warning: unknown camera mode {:?}", other);
+                CameraMode::Follow
+            }
+        };
+
+        MapSettings {
+            map: handle.clone(),
+            scale,
+            background: color("background").unwrap_or(ClearColor::default().0),
+            music: string("music").map(PathBuf::from).or_else(|| config.music.clone()),
+            dialogue: string("dialogue").map_or_else(|| config.start_dialogue.clone(), PathBuf::from),
+            camera,
+            tint: color("tint").unwrap_or(Color::WHITE),
+        }
+    }
+}
+
+// Tiled stores colors as #AARRGGBB.
+fn color_from_argb(argb: u32) -> Color {
+    let channel = |shift: u32| ((argb >> shift) & 0xff) as u8;
+
+    Color::rgba_u8(channel(16), channel(8), channel(0), channel(24))
+}
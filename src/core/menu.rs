@@ -1,17 +1,21 @@
-use bevy::prelude::*;
+use bevy::{ecs::component::Component, prelude::*};
 
-use super::{config::Config, state::{AppState, TransientState}};
+use super::{config::Config, save::latest_save_slot, state::{AppState, TransientState}};
 
 // Tag for the menu system UI.
 struct MenuUi;
 
 pub enum MenuButton {
+    Continue,
     OnePlayer,
     TwoPlayers,
+    Settings,
 }
 
 pub enum MenuAction {
     Nil,
+    // Load the game that was saved last.
+    Continue,
     LoadPlayers { num_players: u8 },
 }
 
@@ -28,6 +32,7 @@ impl Plugin for MenuPlugin {
 
 pub fn menu_system(
     transient_state: ResMut<TransientState>,
+    mut state: ResMut<State<AppState>>,
     mut interaction_query: Query<
         (&Interaction, &mut Handle<ColorMaterial>, &MenuButton),
         (Changed<Interaction>, With<Button>),
@@ -39,18 +44,21 @@ pub fn menu_system(
     {
         match *interaction {
             Interaction::Clicked => match button_choice {
+                MenuButton::Continue => {
+                    action = MenuAction::Continue;
+                }
                 MenuButton::OnePlayer => {
                     action = MenuAction::LoadPlayers { num_players: 1 };
                 }
                 MenuButton::TwoPlayers => {
                     action = MenuAction::LoadPlayers { num_players: 2 };
                 }
+                MenuButton::Settings => {
+                    state.push(AppState::Settings).ok();
+                }
             },
-            Interaction::Hovered => {
-                *material = transient_state.button_hovered_color.clone();
-            }
-            Interaction::None => {
-                *material = transient_state.button_pressed_color.clone();
+            Interaction::Hovered | Interaction::None => {
+                update_button_material(interaction, &mut material, &transient_state);
             }
         }
     }
@@ -83,18 +91,53 @@ fn setup_menu_system(
         .insert(MenuUi {})
         .with_children(|parent| {
             // Title
+            spawn_menu_title(parent, &config.title, &asset_server);
+
+            if latest_save_slot().is_some() {
+                spawn_menu_button(parent, MenuButton::Continue, "Continue", &asset_server, &transient_state);
+            }
+            // Start button 1 player.
+            spawn_menu_button(parent, MenuButton::OnePlayer, "1 Player", &asset_server, &transient_state);
+            // Start button 2 players.
+            spawn_menu_button(parent, MenuButton::TwoPlayers, "2 Players", &asset_server, &transient_state);
+            spawn_menu_button(parent, MenuButton::Settings, "Settings", &asset_server, &transient_state);
+        });
+}
+
+// Spawn a button with a text label.  The button component is used to tell
+// which button was clicked.
+pub fn spawn_menu_button<B: Component>(
+    parent: &mut ChildBuilder,
+    button: B,
+    label: &str,
+    asset_server: &AssetServer,
+    transient_state: &TransientState,
+) {
+    parent
+        .spawn_bundle(ButtonBundle {
+            style: Style {
+                size: Size::new(Val::Px(340.0), Val::Px(65.0)),
+                margin: Rect::all(Val::Px(5.0)),
+                // Horizontally center child text
+                justify_content: JustifyContent::Center,
+                // Vertically center child text
+                align_items: AlignItems::Center,
+                ..Default::default()
+            },
+            material: transient_state.button_color.clone(),
+            ..Default::default()
+        })
+        .insert(button)
+        .with_children(|parent| {
             parent.spawn_bundle(TextBundle {
-                style: Style {
-                    margin: Rect::all(Val::Px(5.0)),
-                    ..Default::default()
-                },
                 text: Text {
                     sections: vec![TextSection {
-                        value: config.title.clone(),
+                        value: label.to_string(),
                         style: TextStyle {
-                            font: asset_server.load("fonts/FiraSans-Bold.ttf"),
-                            font_size: 60.0,
-                            color: Color::BLACK,
+                            font: asset_server
+                                .load("fonts/FiraSans-Bold.ttf"),
+                            font_size: 40.0,
+                            color: Color::rgb(0.9, 0.9, 0.9),
                             ..Default::default()
                         },
                     }],
@@ -102,77 +145,51 @@ fn setup_menu_system(
                 },
                 ..Default::default()
             });
+        });
+}
 
-            // Start button 1 player.
-            parent
-                .spawn_bundle(ButtonBundle {
-                    style: Style {
-                        size: Size::new(Val::Px(170.0), Val::Px(65.0)),
-                        margin: Rect::all(Val::Px(5.0)),
-                        // Horizontally center child text
-                        justify_content: JustifyContent::Center,
-                        // Vertically center child text
-                        align_items: AlignItems::Center,
-                        ..Default::default()
-                    },
-                    material: transient_state.button_color.clone(),
+// Spawn a menu title.
+pub fn spawn_menu_title(
+    parent: &mut ChildBuilder,
+    title: &str,
+    asset_server: &AssetServer,
+) {
+    parent.spawn_bundle(TextBundle {
+        style: Style {
+            margin: Rect::all(Val::Px(5.0)),
+            ..Default::default()
+        },
+        text: Text {
+            sections: vec![TextSection {
+                value: title.to_string(),
+                style: TextStyle {
+                    font: asset_server.load("fonts/FiraSans-Bold.ttf"),
+                    font_size: 60.0,
+                    color: Color::BLACK,
                     ..Default::default()
-                })
-                .insert(MenuButton::OnePlayer)
-                .with_children(|parent| {
-                    parent.spawn_bundle(TextBundle {
-                        text: Text {
-                            sections: vec![TextSection {
-                                value: "1 Player".to_string(),
-                                style: TextStyle {
-                                    font: asset_server
-                                        .load("fonts/FiraSans-Bold.ttf"),
-                                    font_size: 40.0,
-                                    color: Color::rgb(0.9, 0.9, 0.9),
-                                    ..Default::default()
-                                },
-                            }],
-                            ..Default::default()
-                        },
-                        ..Default::default()
-                    });
-                });
+                },
+            }],
+            ..Default::default()
+        },
+        ..Default::default()
+    });
+}
 
-            // Start button 2 players.
-            parent
-                .spawn_bundle(ButtonBundle {
-                    style: Style {
-                        size: Size::new(Val::Px(170.0), Val::Px(65.0)),
-                        margin: Rect::all(Val::Px(5.0)),
-                        // Horizontally center child text
-                        justify_content: JustifyContent::Center,
-                        // Vertically center child text
-                        align_items: AlignItems::Center,
-                        ..Default::default()
-                    },
-                    material: transient_state.button_color.clone(),
-                    ..Default::default()
-                })
-                .insert(MenuButton::TwoPlayers)
-                .with_children(|parent| {
-                    parent.spawn_bundle(TextBundle {
-                        text: Text {
-                            sections: vec![TextSection {
-                                value: "2 Players".to_string(),
-                                style: TextStyle {
-                                    font: asset_server
-                                        .load("fonts/FiraSans-Bold.ttf"),
-                                    font_size: 40.0,
-                                    color: Color::rgb(0.9, 0.9, 0.9),
-                                    ..Default::default()
-                                },
-                            }],
-                            ..Default::default()
-                        },
-                        ..Default::default()
-                    });
-                });
-        });
+// Highlight a button based on its interaction.
+pub fn update_button_material(
+    interaction: &Interaction,
+    material: &mut Handle<ColorMaterial>,
+    transient_state: &TransientState,
+) {
+    match *interaction {
+        Interaction::Clicked => {}
+        Interaction::Hovered => {
+            *material = transient_state.button_hovered_color.clone();
+        }
+        Interaction::None => {
+            *material = transient_state.button_pressed_color.clone();
+        }
+    }
 }
 
 fn cleanup_menu_system(
@@ -0,0 +1,140 @@
+use bevy::{app::AppExit, prelude::*};
+
+use super::{
+    input::{Action, InputActionSet},
+    menu::{spawn_menu_button, spawn_menu_title, update_button_material},
+    state::{AppState, TransientState},
+};
+
+// Tag for the pause menu UI.
+struct PauseUi;
+
+pub enum PauseButton {
+    Resume,
+    Save,
+    Settings,
+    ReturnToTitle,
+    Quit,
+}
+
+// Actions that the app needs to handle itself.  Resuming and quitting are
+// handled by this module.
+pub enum PauseAction {
+    Nil,
+    Save,
+    ReturnToTitle,
+}
+
+#[derive(Debug, Default)]
+pub struct PausePlugin;
+
+impl Plugin for PausePlugin {
+    fn build(&self, app: &mut AppBuilder) {
+        app
+        .add_system_set(SystemSet::on_update(AppState::InGame).with_system(pause_input_system.system()))
+        .add_system_set(SystemSet::on_enter(AppState::Paused).with_system(setup_pause_menu_system.system()))
+        .add_system_set(SystemSet::on_exit(AppState::Paused).with_system(cleanup_pause_menu_system.system()));
+    }
+}
+
+// Pausing pushes a state on top of InGame so that nothing in-game gets
+// re-entered when resuming.
+fn pause_input_system(
+    mut input_actions: ResMut<InputActionSet>,
+    mut state: ResMut<State<AppState>>,
+) {
+    // Consumed so that the menu doesn't see the same press and close again.
+    if input_actions.consume_any(Action::Pause) {
+        // Ignore the error in case another transition is already queued.
+        state.push(AppState::Paused).ok();
+    }
+}
+
+pub fn pause_menu_system(
+    transient_state: Res<TransientState>,
+    mut input_actions: ResMut<InputActionSet>,
+    mut state: ResMut<State<AppState>>,
+    mut app_exit_events: EventWriter<AppExit>,
+    mut interaction_query: Query<
+        (&Interaction, &mut Handle<ColorMaterial>, &PauseButton),
+        (Changed<Interaction>, With<Button>),
+    >,
+) -> PauseAction {
+    // Pressing pause again resumes.
+    if input_actions.consume_any(Action::Pause) {
+        state.pop().ok();
+        return PauseAction::Nil;
+    }
+
+    let mut action = PauseAction::Nil;
+    for (interaction, mut material, button_choice) in
+        interaction_query.iter_mut()
+    {
+        match *interaction {
+            Interaction::Clicked => match button_choice {
+                PauseButton::Resume => {
+                    state.pop().ok();
+                }
+                PauseButton::Save => {
+                    action = PauseAction::Save;
+                }
+                PauseButton::Settings => {
+                    state.push(AppState::Settings).ok();
+                }
+                PauseButton::ReturnToTitle => {
+                    action = PauseAction::ReturnToTitle;
+                }
+                PauseButton::Quit => {
+                    app_exit_events.send(AppExit);
+                }
+            },
+            Interaction::Hovered | Interaction::None => {
+                update_button_material(interaction, &mut material, &transient_state);
+            }
+        }
+    }
+
+    action
+}
+
+fn setup_pause_menu_system(
+    mut commands: Commands,
+    asset_server: Res<AssetServer>,
+    mut materials: ResMut<Assets<ColorMaterial>>,
+    transient_state: Res<TransientState>,
+) {
+    commands
+        // Root
+        .spawn_bundle(NodeBundle {
+            style: Style {
+                size: Size::new(Val::Percent(100.0), Val::Percent(100.0)),
+                flex_direction: FlexDirection::ColumnReverse,
+                // Horizontally center child text
+                justify_content: JustifyContent::Center,
+                // Vertically center child text
+                align_items: AlignItems::Center,
+                ..Default::default()
+            },
+            // Dim the frozen game behind the menu.
+            material: materials.add(Color::rgba(0.0, 0.0, 0.0, 0.5).into()),
+            ..Default::default()
+        })
+        .insert(PauseUi {})
+        .with_children(|parent| {
+            spawn_menu_title(parent, "Paused", &asset_server);
+            spawn_menu_button(parent, PauseButton::Resume, "Resume", &asset_server, &transient_state);
+            spawn_menu_button(parent, PauseButton::Save, "Save", &asset_server, &transient_state);
+            spawn_menu_button(parent, PauseButton::Settings, "Settings", &asset_server, &transient_state);
+            spawn_menu_button(parent, PauseButton::ReturnToTitle, "Return to Title", &asset_server, &transient_state);
+            spawn_menu_button(parent, PauseButton::Quit, "Quit", &asset_server, &transient_state);
+        });
+}
+
+fn cleanup_pause_menu_system(
+    mut commands: Commands,
+    query: Query<Entity, With<PauseUi>>,
+) {
+    for entity in query.iter() {
+        commands.entity(entity).despawn_recursive();
+    }
+}
@@ -0,0 +1,144 @@
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::PathBuf;
+use std::time::SystemTime;
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+
+use super::{config::user_dir, game::ObjectId};
+
+// Bump this when the format changes.  Older saves are rejected in read_save()
+// unless they can be migrated there.  Version 2 keys objects by their ids in
+// the map, which version 1 saves can't be converted to.
+pub const SAVE_VERSION: u32 = 2;
+pub const NUM_SAVE_SLOTS: u32 = 3;
+
+const SAVES_DIR_NAME: &str = "saves";
+
+// Everything needed to continue a game.  Paths are asset paths.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct SaveData {
+    pub version: u32,
+    pub current_map: String,
+    pub players: Vec<PlayerSave>,
+    // Objects that changed during play.
+    pub collected_objects: BTreeSet<ObjectId>,
+    pub revealed_objects: BTreeSet<ObjectId>,
+    pub dialogue_flags: BTreeSet<String>,
+    pub start_dialogue_shown: bool,
+    // Place players at the map's spawn instead of their saved positions.  Used
+    // when saving on the way to a new map.
+    #[serde(default)]
+    pub at_spawn: bool,
+    // Entry object to arrive at instead of the spawn.
+    #[serde(default)]
+    pub entry: Option<String>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct PlayerSave {
+    pub id: u32,
+    pub position: (f32, f32),
+    pub num_gems: u32,
+}
+
+pub fn read_save(slot: u32) -> Result<SaveData> {
+    let path = save_path(slot)?;
+    let contents = fs::read_to_string(path.as_path())
+        .with_context(||
+            format!("error reading save file: {:?}", path.as_os_str())
+        )?;
+
+    parse_save(contents.as_ref())
+        .with_context(||
+            format!("error parsing save file: {:?}", path.as_os_str())
+        )
+}
+
+pub fn parse_save(contents: &str) -> Result<SaveData> {
+    let data: SaveData = ron::de::from_str(contents)?;
+    if data.version != SAVE_VERSION {
+        bail!("save is version {}, but only version {} is supported",
+              data.version, SAVE_VERSION);
+    }
+
+    Ok(data)
+}
+
+pub fn serialize_save(data: &SaveData) -> Result<String> {
+    let contents = ron::ser::to_string_pretty(data, ron::ser::PrettyConfig::new())
+        .context("error serializing save")?;
+
+    Ok(contents)
+}
+
+impl SaveData {
+    pub fn new(current_map: String) -> SaveData {
+        SaveData {
+            version: SAVE_VERSION,
+            current_map,
+            players: Vec::new(),
+            collected_objects: BTreeSet::new(),
+            revealed_objects: BTreeSet::new(),
+            dialogue_flags: BTreeSet::new(),
+            start_dialogue_shown: false,
+            at_spawn: false,
+            entry: None,
+        }
+    }
+}
+
+pub fn write_save(slot: u32, data: &SaveData) -> Result<()> {
+    let path = save_path(slot)?;
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)
+            .with_context(||
+                format!("error creating save directory: {:?}", dir.as_os_str())
+            )?;
+    }
+    let contents = serialize_save(data)?;
+    fs::write(path.as_path(), contents)
+        .with_context(||
+            format!("error writing save file: {:?}", path.as_os_str())
+        )?;
+
+    Ok(())
+}
+
+// The slot that was saved most recently.
+pub fn latest_save_slot() -> Option<u32> {
+    (0..NUM_SAVE_SLOTS)
+        .filter_map(|slot| save_modified(slot).map(|modified| (slot, modified)))
+        .max_by_key(|(_, modified)| *modified)
+        .map(|(slot, _)| slot)
+}
+
+// A slot for a new game.  Use an empty one if there is one so that the
+// previous game isn't overwritten.  Otherwise, reuse the oldest.
+pub fn new_game_slot() -> u32 {
+    let mut oldest: Option<(u32, SystemTime)> = None;
+    for slot in 0..NUM_SAVE_SLOTS {
+        match save_modified(slot) {
+            None => return slot,
+            Some(modified) => {
+                if oldest.map_or(true, |(_, oldest_modified)| modified < oldest_modified) {
+                    oldest = Some((slot, modified));
+                }
+            }
+        }
+    }
+
+    oldest.map_or(0, |(slot, _)| slot)
+}
+
+fn save_modified(slot: u32) -> Option<SystemTime> {
+    let path = save_path(slot).ok()?;
+    fs::metadata(path).and_then(|metadata| metadata.modified()).ok()
+}
+
+fn save_path(slot: u32) -> Result<PathBuf> {
+    let dir = user_dir().context("couldn't find a directory for saves")?;
+
+    Ok(dir.join(SAVES_DIR_NAME).join(format!("slot{}.ron", slot)))
+}
@@ -0,0 +1,486 @@
+use bevy::{
+    prelude::*,
+    window::{WindowDescriptor, WindowMode},
+};
+use serde::{Deserialize, Serialize};
+
+use super::{
+    config::{save_user_settings, Config},
+    input::{default_key_bindings, Action, InputActionSet, KeyBinding},
+    menu::{spawn_menu_button, spawn_menu_title, update_button_material},
+    state::{AppState, TransientState},
+};
+
+const RESOLUTIONS: &[(f32, f32)] = &[
+    (1280.0, 720.0),
+    (1600.0, 900.0),
+    (1920.0, 1080.0),
+    (2560.0, 1440.0),
+];
+
+// Characters per second.  Zero shows all text at once.
+const TEXT_SPEEDS: &[f32] = &[0.0, 20.0, 40.0, 80.0];
+
+const VOLUME_STEP: f32 = 0.1;
+
+// User preferences that are persisted separately from the asset config.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Settings {
+    pub master_volume: f32,
+    pub music_volume: f32,
+    pub sfx_volume: f32,
+
+    pub window_mode: WindowModeSetting,
+    pub window_width: f32,
+    pub window_height: f32,
+
+    pub text_speed: f32,
+    // Dialogue switches to it as soon as it changes.
+    pub language: String,
+
+    // Keep this last since TOML tables can't be followed by plain values.
+    pub key_bindings: Vec<KeyBinding>,
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
+pub enum WindowModeSetting {
+    Windowed,
+    Borderless,
+    Fullscreen,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            master_volume: 1.0,
+            music_volume: 0.7,
+            sfx_volume: 1.0,
+
+            window_mode: WindowModeSetting::Windowed,
+            window_width: RESOLUTIONS[0].0,
+            window_height: RESOLUTIONS[0].1,
+
+            text_speed: 40.0,
+            language: "en".to_string(),
+
+            key_bindings: default_key_bindings(),
+        }
+    }
+}
+
+impl Settings {
+    pub fn window_descriptor(&self, title: &str) -> WindowDescriptor {
+        WindowDescriptor {
+            title: title.to_string(),
+            width: self.window_width,
+            height: self.window_height,
+            mode: self.window_mode.into(),
+            ..Default::default()
+        }
+    }
+}
+
+impl From<WindowModeSetting> for WindowMode {
+    fn from(mode: WindowModeSetting) -> Self {
+        match mode {
+            WindowModeSetting::Windowed => WindowMode::Windowed,
+            WindowModeSetting::Borderless => WindowMode::BorderlessFullscreen,
+            WindowModeSetting::Fullscreen => WindowMode::Fullscreen { use_size: true },
+        }
+    }
+}
+
+// Tag for the settings menu UI.
+struct SettingsUi;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum SettingKind {
+    MasterVolume,
+    MusicVolume,
+    SfxVolume,
+    WindowMode,
+    Resolution,
+    TextSpeed,
+    Language,
+    KeyBinding,
+}
+
+pub enum SettingsButton {
+    Decrease(SettingKind),
+    Increase(SettingKind),
+    // Wait for a key press to replace the selected key binding.
+    Rebind,
+    Back,
+}
+
+// Text that displays the current value of a setting.
+struct SettingValueText(SettingKind);
+
+// State of the menu that isn't a setting itself.
+#[derive(Debug, Default)]
+struct SettingsMenuState {
+    selected_binding: usize,
+    is_rebinding: bool,
+}
+
+#[derive(Debug, Default)]
+pub struct SettingsPlugin;
+
+impl Plugin for SettingsPlugin {
+    fn build(&self, app: &mut AppBuilder) {
+        app
+        .insert_resource(SettingsMenuState::default())
+        .add_system(apply_window_settings_system.system())
+        .add_system_set(SystemSet::on_enter(AppState::Settings).with_system(setup_settings_menu_system.system()))
+        .add_system_set(SystemSet::on_update(AppState::Settings)
+            .with_system(settings_menu_system.system().label("settings"))
+            .with_system(update_setting_text_system.system().after("settings"))
+        )
+        .add_system_set(SystemSet::on_exit(AppState::Settings)
+            .with_system(cleanup_settings_menu_system.system())
+            .with_system(save_settings_system.system())
+        );
+    }
+}
+
+// Apply window settings whenever they change.
+fn apply_window_settings_system(
+    config: Res<Config>,
+    mut windows: ResMut<Windows>,
+) {
+    if !config.is_changed() {
+        return;
+    }
+    if let Some(window) = windows.get_primary_mut() {
+        let settings = &config.settings;
+        if window.mode() != settings.window_mode.into() {
+            window.set_mode(settings.window_mode.into());
+        }
+        if window.requested_width() != settings.window_width ||
+           window.requested_height() != settings.window_height {
+            window.set_resolution(settings.window_width, settings.window_height);
+        }
+    }
+}
+
+fn settings_menu_system(
+    transient_state: Res<TransientState>,
+    keyboard_input: Res<Input<KeyCode>>,
+    mut input_actions: ResMut<InputActionSet>,
+    mut menu_state: ResMut<SettingsMenuState>,
+    mut state: ResMut<State<AppState>>,
+    mut config: ResMut<Config>,
+    mut interaction_query: Query<
+        (&Interaction, &mut Handle<ColorMaterial>, &SettingsButton),
+        (Changed<Interaction>, With<Button>),
+    >,
+) {
+    if menu_state.is_rebinding {
+        if let Some(key) = keyboard_input.get_just_pressed().next() {
+            // Escape cancels since it's needed to get out of menus.
+            if *key != KeyCode::Escape {
+                let index = menu_state.selected_binding;
+                if let Some(binding) = config.settings.key_bindings.get_mut(index) {
+                    binding.key = *key;
+                }
+            }
+            menu_state.is_rebinding = false;
+        }
+        // Escape only cancels rebinding, so it shouldn't leave the menu too.
+        input_actions.consume_any(Action::Pause);
+        return;
+    }
+
+    // Pause leaves the menu like Back.
+    if input_actions.consume_any(Action::Pause) {
+        state.pop().ok();
+        return;
+    }
+
+    for (interaction, mut material, button_choice) in
+        interaction_query.iter_mut()
+    {
+        match *interaction {
+            Interaction::Clicked => match button_choice {
+                SettingsButton::Decrease(kind) => {
+                    change_setting(&mut config, &mut menu_state, *kind, -1);
+                }
+                SettingsButton::Increase(kind) => {
+                    change_setting(&mut config, &mut menu_state, *kind, 1);
+                }
+                SettingsButton::Rebind => {
+                    let binding = config.settings.key_bindings.get(menu_state.selected_binding);
+                    if binding.map_or(false, |binding| binding.action.is_rebindable()) {
+                        menu_state.is_rebinding = true;
+                    }
+                }
+                SettingsButton::Back => {
+                    state.pop().ok();
+                }
+            },
+            Interaction::Hovered | Interaction::None => {
+                update_button_material(interaction, &mut material, &transient_state);
+            }
+        }
+    }
+}
+
+fn change_setting(
+    config: &mut Config,
+    menu_state: &mut SettingsMenuState,
+    kind: SettingKind,
+    step: i32,
+) {
+    match kind {
+        SettingKind::MasterVolume => {
+            config.settings.master_volume = step_volume(config.settings.master_volume, step);
+        }
+        SettingKind::MusicVolume => {
+            config.settings.music_volume = step_volume(config.settings.music_volume, step);
+        }
+        SettingKind::SfxVolume => {
+            config.settings.sfx_volume = step_volume(config.settings.sfx_volume, step);
+        }
+        SettingKind::WindowMode => {
+            let modes = [
+                WindowModeSetting::Windowed,
+                WindowModeSetting::Borderless,
+                WindowModeSetting::Fullscreen,
+            ];
+            let index = cycle_index(&modes, &config.settings.window_mode, step);
+            config.settings.window_mode = modes[index];
+        }
+        SettingKind::Resolution => {
+            let current = (config.settings.window_width, config.settings.window_height);
+            let index = cycle_index(RESOLUTIONS, &current, step);
+            config.settings.window_width = RESOLUTIONS[index].0;
+            config.settings.window_height = RESOLUTIONS[index].1;
+        }
+        SettingKind::TextSpeed => {
+            let index = cycle_index(TEXT_SPEEDS, &config.settings.text_speed, step);
+            config.settings.text_speed = TEXT_SPEEDS[index];
+        }
+        SettingKind::Language => {
+            let index = cycle_index(&config.languages, &config.settings.language, step);
+            if let Some(language) = config.languages.get(index) {
+                config.settings.language = language.clone();
+            }
+        }
+        SettingKind::KeyBinding => {
+            let len = config.settings.key_bindings.len();
+            if len > 0 {
+                let index = menu_state.selected_binding as i32 + step;
+                menu_state.selected_binding = index.rem_euclid(len as i32) as usize;
+            }
+        }
+    }
+}
+
+fn step_volume(volume: f32, step: i32) -> f32 {
+    let new_volume = volume + VOLUME_STEP * step as f32;
+    // Round so that repeated steps don't accumulate error.
+    ((new_volume / VOLUME_STEP).round() * VOLUME_STEP).clamp(0.0, 1.0)
+}
+
+// Index of the item after stepping from the current one, wrapping around.  If
+// the current value isn't in the list, start from the beginning.
+fn cycle_index<T: PartialEq>(items: &[T], current: &T, step: i32) -> usize {
+    if items.is_empty() {
+        return 0;
+    }
+    let len = items.len() as i32;
+    match items.iter().position(|item| item == current) {
+        Some(index) => (index as i32 + step).rem_euclid(len) as usize,
+        None => 0,
+    }
+}
+
+fn setting_value_text(config: &Config, menu_state: &SettingsMenuState, kind: SettingKind) -> String {
+    let settings = &config.settings;
+    match kind {
+        SettingKind::MasterVolume => format!("{:.0}%", settings.master_volume * 100.0),
+        SettingKind::MusicVolume => format!("{:.0}%", settings.music_volume * 100.0),
+        SettingKind::SfxVolume => format!("{:.0}%", settings.sfx_volume * 100.0),
+        SettingKind::WindowMode => format!("{:?}", settings.window_mode),
+        SettingKind::Resolution => format!("{}x{}", settings.window_width, settings.window_height),
+        SettingKind::TextSpeed => {
+            if settings.text_speed <= 0.0 {
+                "Instant".to_string()
+            } else {
+                format!("{} chars/s", settings.text_speed)
+            }
+        }
+        SettingKind::Language => settings.language.clone(),
+        SettingKind::KeyBinding => {
+            match settings.key_bindings.get(menu_state.selected_binding) {
+                None => "None".to_string(),
+                Some(binding) if menu_state.is_rebinding => {
+                    format!("P{} {:?}: press a key", binding.player + 1, binding.action)
+                }
+                Some(binding) => {
+                    format!("P{} {:?}: {:?}", binding.player + 1, binding.action, binding.key)
+                }
+            }
+        }
+    }
+}
+
+fn update_setting_text_system(
+    config: Res<Config>,
+    menu_state: Res<SettingsMenuState>,
+    mut text_query: Query<(&mut Text, &SettingValueText)>,
+) {
+    if !config.is_changed() && !menu_state.is_changed() {
+        return;
+    }
+    for (mut text, value_text) in text_query.iter_mut() {
+        text.sections[0].value = setting_value_text(&config, &menu_state, value_text.0);
+    }
+}
+
+fn setup_settings_menu_system(
+    mut commands: Commands,
+    asset_server: Res<AssetServer>,
+    mut materials: ResMut<Assets<ColorMaterial>>,
+    transient_state: Res<TransientState>,
+    config: Res<Config>,
+    mut menu_state: ResMut<SettingsMenuState>,
+) {
+    *menu_state = SettingsMenuState::default();
+    let rows = [
+        ("Master Volume", SettingKind::MasterVolume),
+        ("Music Volume", SettingKind::MusicVolume),
+        ("Effects Volume", SettingKind::SfxVolume),
+        ("Window", SettingKind::WindowMode),
+        ("Resolution", SettingKind::Resolution),
+        ("Text Speed", SettingKind::TextSpeed),
+        ("Language", SettingKind::Language),
+        ("Keys", SettingKind::KeyBinding),
+    ];
+    let font = asset_server.load("fonts/FiraSans-Bold.ttf");
+    let text_style = TextStyle {
+        font,
+        font_size: 30.0,
+        color: Color::rgb(0.9, 0.9, 0.9),
+        ..Default::default()
+    };
+
+    commands
+        // Root
+        .spawn_bundle(NodeBundle {
+            style: Style {
+                size: Size::new(Val::Percent(100.0), Val::Percent(100.0)),
+                flex_direction: FlexDirection::ColumnReverse,
+                // Horizontally center child text
+                justify_content: JustifyContent::Center,
+                // Vertically center child text
+                align_items: AlignItems::Center,
+                ..Default::default()
+            },
+            // Cover whatever menu opened this one.
+            material: materials.add(Color::rgb(0.8, 0.8, 0.8).into()),
+            ..Default::default()
+        })
+        .insert(SettingsUi {})
+        .with_children(|parent| {
+            spawn_menu_title(parent, "Settings", &asset_server);
+
+            for (label, kind) in rows.iter() {
+                // One row for each setting.
+                parent.spawn_bundle(NodeBundle {
+                    style: Style {
+                        flex_direction: FlexDirection::Row,
+                        align_items: AlignItems::Center,
+                        margin: Rect::all(Val::Px(2.0)),
+                        ..Default::default()
+                    },
+                    material: materials.add(Color::NONE.into()),
+                    ..Default::default()
+                })
+                .with_children(|parent| {
+                    parent.spawn_bundle(TextBundle {
+                        style: Style {
+                            size: Size::new(Val::Px(220.0), Val::Auto),
+                            ..Default::default()
+                        },
+                        text: Text::with_section(*label, TextStyle {
+                            color: Color::BLACK,
+                            ..text_style.clone()
+                        }, Default::default()),
+                        ..Default::default()
+                    });
+                    spawn_small_button(parent, SettingsButton::Decrease(*kind), "<", &text_style, &transient_state);
+                    parent.spawn_bundle(TextBundle {
+                        style: Style {
+                            size: Size::new(Val::Px(320.0), Val::Auto),
+                            margin: Rect::all(Val::Px(5.0)),
+                            ..Default::default()
+                        },
+                        text: Text::with_section(
+                            setting_value_text(&config, &menu_state, *kind),
+                            TextStyle {
+                                color: Color::BLACK,
+                                ..text_style.clone()
+                            },
+                            Default::default(),
+                        ),
+                        ..Default::default()
+                    })
+                    .insert(SettingValueText(*kind));
+                    spawn_small_button(parent, SettingsButton::Increase(*kind), ">", &text_style, &transient_state);
+                    if *kind == SettingKind::KeyBinding {
+                        spawn_small_button(parent, SettingsButton::Rebind, "Set", &text_style, &transient_state);
+                    }
+                });
+            }
+
+            spawn_menu_button(parent, SettingsButton::Back, "Back", &asset_server, &transient_state);
+        });
+}
+
+fn spawn_small_button(
+    parent: &mut ChildBuilder,
+    button: SettingsButton,
+    label: &str,
+    text_style: &TextStyle,
+    transient_state: &TransientState,
+) {
+    parent
+        .spawn_bundle(ButtonBundle {
+            style: Style {
+                size: Size::new(Val::Px(60.0), Val::Px(40.0)),
+                margin: Rect::all(Val::Px(2.0)),
+                // Horizontally center child text
+                justify_content: JustifyContent::Center,
+                // Vertically center child text
+                align_items: AlignItems::Center,
+                ..Default::default()
+            },
+            material: transient_state.button_color.clone(),
+            ..Default::default()
+        })
+        .insert(button)
+        .with_children(|parent| {
+            parent.spawn_bundle(TextBundle {
+                text: Text::with_section(label, text_style.clone(), Default::default()),
+                ..Default::default()
+            });
+        });
+}
+
+// Save however the menu was left.
+fn save_settings_system(config: Res<Config>) {
+    if let Err(err) = save_user_settings(&config.settings) {
+        eprintln!("Warning: couldn't save settings: {:?}", err);
+    }
+}
+
+fn cleanup_settings_menu_system(
+    mut commands: Commands,
+    query: Query<Entity, With<SettingsUi>>,
+) {
+    for entity in query.iter() {
+        commands.entity(entity).despawn_recursive();
+    }
+}
@@ -8,6 +8,10 @@ pub enum AppState {
     Loading,
     Menu,
     InGame,
+    // Pushed on top of InGame so that in-game systems stop running.
+    Paused,
+    // Pushed on top of whichever menu opened it.
+    Settings,
 }
 
 impl Default for AppState {
@@ -1,8 +1,10 @@
-use bevy::prelude::*;
+use bevy::{prelude::*, utils::HashMap};
+use bevy_tiled_prototype::Map;
 
-use crate::core::character::Character;
+use crate::core::{character::Character, game::Game, state::TransientState};
 use crate::items::Inventory;
 use crate::players::Player;
+use crate::scene2d::map_path;
 
 // Debug entities will be marked with this so that we can despawn them all when
 // debug mode is turned off.
@@ -13,6 +15,9 @@ pub struct PlayerPositionDisplay {
     pub player_id: u32,
 }
 
+// Text showing how many entities each spawned map has.
+pub struct MapStatsDisplay;
+
 
 pub fn position_display_system(
     mut character_query: Query<(&Transform, &Player, &Character, &Inventory)>,
@@ -34,3 +39,47 @@ pub fn position_display_system(
         }
     }
 }
+
+pub fn map_stats_display_system(
+    transient_state: Res<TransientState>,
+    game_state: Res<Game>,
+    asset_server: Res<AssetServer>,
+    map_entity_query: Query<(Entity, &Handle<Map>, Option<&Parent>)>,
+    children_query: Query<&Children>,
+    mut text_query: Query<&mut Text, With<MapStatsDisplay>>,
+) {
+    if !transient_state.debug_mode {
+        return;
+    }
+    // Count from the outermost entities of each map so that children spawned
+    // without the map's handle, like tiles and embedded objects, are counted
+    // too.
+    let mut counts: HashMap<Handle<Map>, usize> = HashMap::default();
+    for (entity, map, parent_option) in map_entity_query.iter() {
+        let has_map_parent = parent_option
+            .map_or(false, |parent| map_entity_query.get(parent.0).is_ok());
+        if has_map_parent {
+            continue;
+        }
+        let mut count = 0;
+        let mut stack = vec![entity];
+        while let Some(entity) = stack.pop() {
+            count += 1;
+            if let Ok(children) = children_query.get(entity) {
+                stack.extend(children.iter().cloned());
+            }
+        }
+        *counts.entry(map.clone()).or_insert(0) += count;
+    }
+    let mut lines: Vec<String> = counts.iter()
+        .map(|(map, count)| {
+            let name = map_path(&asset_server, map).unwrap_or_else(|| "?".to_string());
+            let current = if *map == game_state.current_map { " (current)" } else { "" };
+            format!("{}: {} entities{}", name, count, current)
+        })
+        .collect();
+    lines.sort();
+    for mut text in text_query.iter_mut() {
+        text.sections[0].value = format!("Maps: {}", lines.join(", "));
+    }
+}
@@ -1,10 +1,8 @@
-use std::fs;
-
-use bevy::{asset::FileAssetIo, prelude::*};
+use bevy::prelude::*;
 use bevy::utils::HashSet;
-use bevy_tiled_prototype::{Map, Object};
+use bevy_tiled_prototype::{Map, Object, PropertyValue};
 
-use crate::{core::{config::Config,collider::{Collider, ColliderBehavior}, dialogue::{Dialogue, DialogueEvent}, game::{DialogueSpec, Game}, state::{AppState, TransientState}}, loading::LoadProgress, scene2d::load_next_map};
+use crate::{core::{audio::SoundEvent, collider::{Collider, ColliderBehavior}, dialogue::{Dialogue, DialogueEvent}, game::{Game, ObjectId}, state::AppState}, loading::LoadProgress, objects::ObjectTypeRegistry, players::Player, saving::{capture_save, save_game}, scene2d::{destination_map_path, load_next_map, split_destination}, transition::{ScreenTransition, TransitionKind}};
 
 #[derive(Debug, Default)]
 pub struct ItemsPlugin;
@@ -19,6 +17,8 @@ impl Plugin for ItemsPlugin {
                 .with_system(items_system.system().label("main"))
                 .with_system(trigger_dialogue_system.system().label("main"))
                 .with_system(inventory_item_reveal_system.system().label("main"))
+                .with_system(checkpoint_system.system().label("main"))
+                .with_system(interaction_sound_system.system())
             );
     }
 }
@@ -28,16 +28,32 @@ pub struct Inventory {
     pub num_gems: u32,
 }
 
-// Event to specify that an actor should pick up an item and equip it.
+// Event to specify that an actor touched an object, e.g. to pick up an item
+// and equip it.
 #[derive(Debug)]
 pub struct ItemInteraction {
     actor: Entity,
     object: Entity,
     behaviors: HashSet<ColliderBehavior>,
+    phase: InteractionPhase,
+}
+
+// Sent once when an actor starts touching an object, every frame while it
+// keeps touching, and once when it stops.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum InteractionPhase {
+    Enter,
+    Stay,
+    Exit,
 }
 
 impl ItemInteraction {
-    pub fn new(actor: Entity, object: Entity, behaviors: HashSet<ColliderBehavior>) -> ItemInteraction {
+    pub fn new(
+        actor: Entity,
+        object: Entity,
+        behaviors: HashSet<ColliderBehavior>,
+        phase: InteractionPhase,
+    ) -> ItemInteraction {
         // An entity can't pick up itself.
         assert!(actor != object);
 
@@ -45,8 +61,13 @@ impl ItemInteraction {
             actor,
             object,
             behaviors,
+            phase,
         }
     }
+
+    pub fn is_enter(&self) -> bool {
+        self.phase == InteractionPhase::Enter
+    }
 }
 
 pub fn trigger_level_load_system(
@@ -57,43 +78,61 @@ pub fn trigger_level_load_system(
     mut game_state: ResMut<Game>,
     mut to_load: ResMut<LoadProgress>,
     // mut entity_query: Query<(Entity, &Handle<Map>, &mut Visible, Option<&TileMapChunk>)>,
-    // Todo: probably removed when level worflow improved
-    transient_state: Res<TransientState>,
-    config: Res<Config>,
+    player_query: Query<(&Player, &Transform, &Inventory)>,
+    dialogue_query: Query<&Dialogue>,
+    object_query: Query<&Object>,
+    mut transition: ResMut<ScreenTransition>,
 ) {
-    for interaction in interaction_reader.iter() {
+    // Cover the screen first.  The map changes once it's covered.
+    for interaction in interaction_reader.iter().filter(|i| i.is_enter()) {
         for behavior in interaction.behaviors.iter() {
             match behavior {
                 ColliderBehavior::Load { path } => {
-                    let level: String = path.to_owned() + &String::from(".tmx");
-
-                    // get asset directory relative to executable - all maps need to be in maps/ for now
-                    let mut asset_path = FileAssetIo::get_root_path();
-                    asset_path.push("assets");
-                    asset_path.push("maps");
-                    asset_path.push(level.clone());
-                    let level_fs_result = fs::metadata(asset_path.clone());
-
-                    // if this file exists, we're going to want to try loading a state
-                    if level_fs_result.is_ok() && state.set(AppState::Loading).is_ok() {
-                        debug!("Loading level... {}", level);
-                        // eventually do preloading:
-                        // game_state.next_map = Some(asset_server.load(level.as_str()));
-                        game_state.current_map = to_load.add(asset_server.load(format!("maps/{}", level).as_str()));
-                        load_next_map(&mut commands, &mut game_state, &transient_state, &config);
-                        to_load.next_state = AppState::InGame;
-                        to_load.next_dialogue = Some(path.clone());
-                    } else {
-                        println!("couldn't load level '{}' as {}", path, asset_path.to_string_lossy());
+                    let kind = match object_query.get(interaction.object).ok()
+                        .and_then(|object| object.props.get("transition"))
+                    {
+                        Some(PropertyValue::StringValue(name)) => TransitionKind::parse(name)
+                            .unwrap_or_else(|| {
+                                eprintln!("Warning: unknown transition '{}' to {}", name, path);
+                                TransitionKind::default()
+                            }),
+                        _ => TransitionKind::default(),
                     };
+                    transition.begin(kind, path.clone());
                 }
 
                 ColliderBehavior::Obstruct |
                 ColliderBehavior::Collect |
-                ColliderBehavior::Dialogue(_) => {}
+                ColliderBehavior::Dialogue(_) |
+                ColliderBehavior::Checkpoint => {}
             }
         }
     }
+
+    if let Some(path) = transition.take_destination() {
+        // "map#entry" arrives at a named entry instead of the spawn
+        let (map_name, entry) = split_destination(&path);
+        if state.set(AppState::Loading).is_ok() {
+            debug!("Loading level... {}", map_name);
+            // usually already loaded in the background
+            let map_path = destination_map_path(map_name);
+            let map = game_state.next_maps.get(&map_path).cloned()
+                .unwrap_or_else(|| asset_server.load(map_path.as_str()));
+            game_state.current_map = to_load.add(map);
+            game_state.entry = entry;
+            load_next_map(&mut commands, &mut game_state);
+            to_load.next_state = AppState::InGame;
+            to_load.next_dialogue = Some(map_name.to_string());
+            // Autosave as if the players had arrived at the new map's spawn
+            // or entry, once it has loaded.
+            if let Some(mut data) = capture_save(&game_state, &asset_server, &player_query, &dialogue_query) {
+                data.at_spawn = true;
+                to_load.pending_save = Some((game_state.save_slot, data));
+            }
+        } else {
+            transition.uncover();
+        }
+    }
 }
 
 // handles consume and equip
@@ -102,27 +141,26 @@ pub fn items_system(
     mut interaction_reader: EventReader<ItemInteraction>,
     mut collider_query: Query<&mut Collider>,
     mut inventory_query: Query<&mut Inventory>,
-    object_query: Query<&Object>,
-    asset_server: Res<AssetServer>,
-    audio: Res<Audio>,
+    object_query: Query<(&Object, Option<&ObjectId>)>,
+    mut game: ResMut<Game>,
+    object_types: Res<ObjectTypeRegistry>,
 ) {
-    for interaction in interaction_reader.iter() {
+    for interaction in interaction_reader.iter().filter(|i| i.is_enter()) {
         for behavior in interaction.behaviors.iter() {
             match behavior {
                 ColliderBehavior::Collect => {
                     commands.entity(interaction.object).despawn_recursive();
+                    // Remember so that it stays collected after loading a save.
+                    if let Ok((_, Some(object_id))) = object_query.get(interaction.object) {
+                        game.collected_objects.insert(object_id.clone());
+                    }
                     if let Ok(mut inventory) = inventory_query.get_mut(interaction.actor) {
-                        inventory.num_gems += 1;
-                        // might wish to use type AND name eventually
-                        if let Ok(obj) = object_query.get(interaction.object) {
-                            let sfx_path = match obj.name.as_str() {
-                                "biggem" => {
-                                    inventory.num_gems += 4; // big gems worth 5 - should be param..
-                                    "sfx/gem_big.ogg"
-                                },
-                                _ => "sfx/gem_small.ogg"
-                            };
-                            audio.play(asset_server.load(sfx_path));
+                        // the kind of object decides what it's worth
+                        let prefab_option = object_query.get(interaction.object).ok()
+                            .and_then(|(obj, _)| object_types.prefab(obj))
+                            .map(|(prefab, _)| prefab);
+                        if let Some(prefab) = prefab_option {
+                            inventory.num_gems += prefab.value;
                         }
                     }
                     // Prevent getting collected again.
@@ -132,24 +170,80 @@ pub fn items_system(
                 }
                 ColliderBehavior::Obstruct |
                 ColliderBehavior::Load { path: _ } |
-                ColliderBehavior::Dialogue(_) => {}
+                ColliderBehavior::Dialogue(_) |
+                ColliderBehavior::Checkpoint => {}
             }
         }
     }
 }
 
+// Sounds for touching objects.  An object's "sfx" property replaces the sound
+// of its kind.
+pub fn interaction_sound_system(
+    mut interaction_reader: EventReader<ItemInteraction>,
+    object_query: Query<&Object>,
+    object_types: Res<ObjectTypeRegistry>,
+    mut sound_events: EventWriter<SoundEvent>,
+) {
+    for interaction in interaction_reader.iter().filter(|i| i.is_enter()) {
+        let object = object_query.get(interaction.object).ok();
+        for behavior in interaction.behaviors.iter() {
+            let name = match behavior {
+                ColliderBehavior::Collect => object
+                    .and_then(|object| object_types.prefab(object))
+                    .and_then(|(prefab, _)| prefab.sfx.clone())
+                    .unwrap_or_else(|| "collect".to_string()),
+                ColliderBehavior::Checkpoint => "checkpoint".to_string(),
+                ColliderBehavior::Load { path: _ } => "portal".to_string(),
+                ColliderBehavior::Obstruct |
+                ColliderBehavior::Dialogue(_) => continue,
+            };
+            let name = match object.and_then(|object| object.props.get("sfx")) {
+                Some(PropertyValue::StringValue(sfx)) => sfx.clone(),
+                _ => name,
+            };
+            sound_events.send(SoundEvent::new(name));
+        }
+    }
+}
+
+// Save when a player first touches a checkpoint.
+pub fn checkpoint_system(
+    mut interaction_reader: EventReader<ItemInteraction>,
+    game: Res<Game>,
+    asset_server: Res<AssetServer>,
+    player_query: Query<(&Player, &Transform, &Inventory)>,
+    dialogue_query: Query<&Dialogue>,
+) {
+    let mut should_save = false;
+    for interaction in interaction_reader.iter().filter(|i| i.is_enter()) {
+        // Only players can save.
+        if interaction.behaviors.contains(&ColliderBehavior::Checkpoint)
+            && player_query.get(interaction.actor).is_ok()
+        {
+            should_save = true;
+        }
+    }
+
+    if should_save {
+        save_game(&game, &asset_server, &player_query, &dialogue_query);
+    }
+}
+
 pub fn trigger_dialogue_system(
     mut interaction_reader: EventReader<ItemInteraction>,
     mut dialogue_query: Query<&mut Dialogue>,
     mut dialogue_events: EventWriter<DialogueEvent>,
     mut game: ResMut<Game>,
 ) {
-    for interaction in interaction_reader.iter() {
+    // Auto-displayed dialogue only begins when first touched.
+    for interaction in interaction_reader.iter().filter(|i| i.is_enter()) {
         for behavior in interaction.behaviors.iter() {
             match behavior {
                 ColliderBehavior::Obstruct => {}
                 ColliderBehavior::Collect => {}
                 ColliderBehavior::Load { path: _ } => {}
+                ColliderBehavior::Checkpoint => {}
                 ColliderBehavior::Dialogue(spec) => {
                     if spec.auto_display {
                         for mut dialogue in dialogue_query.iter_mut() {
@@ -164,46 +258,53 @@ pub fn trigger_dialogue_system(
     }
 }
 
-// ideally this returns early if this level has no items that need monitoring
+// Give a hidden object the behaviors its kind has once it's revealed.  Returns
+// false if its kind can't be revealed.
+pub fn reveal_collider(object: &Object, collider: &mut Collider, object_types: &ObjectTypeRegistry) -> bool {
+    let behaviors = match object_types.revealed_behaviors(object) {
+        None => return false,
+        Some(behaviors) => behaviors,
+    };
+    collider.behaviors.clear();
+    for behavior in behaviors {
+        collider.insert_behavior(behavior);
+    }
+
+    true
+}
+
+// Reveal hidden objects once players have enough gems for their kind.
 pub fn inventory_item_reveal_system(
     mut inventory_query: Query<&mut Inventory>,
-    mut object_query: Query<(&Object, &mut Visible, &mut Collider, &Handle<Map>)>,
-    game: Res<Game>,
+    mut object_query: Query<(&Object, &mut Visible, &mut Collider, &Handle<Map>, Option<&ObjectId>)>,
+    mut game: ResMut<Game>,
+    object_types: Res<ObjectTypeRegistry>,
 ) {
-    let mut do_reveal = false;
-    let mut total_gems = 0;
-    for inventory in inventory_query.iter_mut() {
-        total_gems += inventory.num_gems;
-    }
-    if total_gems >= 4 {
-        do_reveal = true;
-    }
-    if do_reveal {
-        for (object, mut visible, mut collider, map_handle) in object_query.iter_mut() {
-            // only reveal if it's invisible
-            if visible.is_visible {
-                continue;
-            }
-            if total_gems >= 8 && object.name.starts_with("load:") {
-                // show hidden portals when you have enough gems
-                visible.is_visible = true;
-                collider.insert_behavior(ColliderBehavior::Load { path: object.name[5..].to_string() });
-                // clear inventory for new map
-                for mut items in inventory_query.iter_mut() {
-                    items.num_gems = 0;
+    let mut total_gems: u32 = inventory_query.iter_mut().map(|inventory| inventory.num_gems).sum();
+    for (object, mut visible, mut collider, map_handle, object_id) in object_query.iter_mut() {
+        // only reveal if it's invisible
+        if visible.is_visible {
+            continue;
+        }
+        let reveal = match object_types.prefab(object).and_then(|(prefab, _)| prefab.reveal.as_ref()) {
+            None => continue,
+            Some(reveal) => reveal,
+        };
+        if total_gems < reveal.gems || (reveal.current_map_only && *map_handle != game.current_map) {
+            continue;
+        }
+
+        if reveal_collider(object, &mut collider, &object_types) {
+            visible.is_visible = true;
+            if reveal.spend_gems {
+                for mut inventory in inventory_query.iter_mut() {
+                    inventory.num_gems = 0;
                 }
+                total_gems = 0;
             }
-
-            if object.name == "biggem" && *map_handle == game.current_map {
-                visible.is_visible = true;
-                collider.behaviors.clear();
-                collider.insert_behavior(ColliderBehavior::Collect);
-                collider.insert_behavior(ColliderBehavior::Dialogue(
-                    DialogueSpec {
-                        node_name: "collectedBigGem".to_string(),
-                        ui_type: crate::core::game::DialogueUiType::Notice,
-                        auto_display: true,
-                }));
+            // Remember so that it stays revealed after loading a save.
+            if let Some(object_id) = object_id {
+                game.revealed_objects.insert(object_id.clone());
             }
         }
     }
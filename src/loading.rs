@@ -1,15 +1,22 @@
 
-use bevy::{asset::{Asset, HandleId}, prelude::*, utils::HashSet};
+use std::path::Path;
+
+use bevy::{asset::{Asset, HandleId, LoadState}, prelude::*, utils::HashSet};
 use bevy_tiled_prototype::{MapReadyEvent, Object, ObjectReadyEvent, ObjectShape, PropertyValue};
 
-use crate::{core::{config::Config, collider::{Collider, ColliderBehavior}, dialogue::{Dialogue, DialogueEvent}, game::{DialogueSpec, DialogueUiType, Game}, state::AppState}, debug::Debuggable, motion::z_from_y};
+use crate::{core::{collider::{Collider, ColliderBehavior, ColliderShape, CollisionLayers}, dialogue::{Dialogue, DialogueAsset, DialogueEvent}, game::{DialogueSpec, DialogueUiType, Game, ObjectId}, save::SaveData, state::AppState}, debug::Debuggable, items::reveal_collider, motion::z_from_y, objects::ObjectTypeRegistry, saving::write_save_reporting, scene2d::map_path};
 
 #[derive(Debug, Default)]
 pub struct LoadProgress {
     handles: HashSet<HandleUntyped>,
     pub next_state: AppState,
     pub next_dialogue: Option<String>,
-    // progress: f32,
+    // Fraction of the handles loaded and maps spawned, from 0 to 1.
+    pub progress: f32,
+    // The most maps seen spawning at once during this load.
+    spawning_maps: usize,
+    // Written once loading succeeds, e.g. an autosave on arriving at a map.
+    pub pending_save: Option<(u32, SaveData)>,
 }
 
 impl LoadProgress {
@@ -21,9 +28,36 @@ impl LoadProgress {
     pub fn reset(&mut self) {
         self.handles.clear();
         self.next_dialogue = None;
+        self.progress = 0.0;
+        self.spawning_maps = 0;
+        self.pending_save = None;
+    }
+
+    pub fn remove(&mut self, handle: &HandleUntyped) {
+        self.handles.remove(handle);
+    }
+
+    pub fn failed_handles(&self, asset_server: &AssetServer) -> Vec<HandleUntyped> {
+        self.handles.iter()
+            .filter(|handle| asset_server.get_load_state(*handle) == LoadState::Failed)
+            .cloned()
+            .collect()
     }
 }
 
+// Seconds in the Loading state before the loading screen shows, so that it
+// doesn't flash when everything was already loaded.
+const LOADING_SCREEN_DELAY: f32 = 0.25;
+const PROGRESS_BAR_WIDTH: f32 = 300.0;
+
+pub struct LoadingScreen {
+    shown_after: Timer,
+}
+
+pub struct LoadingMapName;
+
+pub struct LoadingProgressBar;
+
 // prevents existing Loading state just from asset load (e.g. map needs spawn)
 pub struct ComplicatedLoad;
 
@@ -34,29 +68,172 @@ pub fn wait_for_asset_loading_system(
     loading_map_query: Query<Entity, With<ComplicatedLoad>>,
     mut dialogue_query: Query<&mut Dialogue>,
     mut dialogue_events: EventWriter<DialogueEvent>,
+    game_state: Res<Game>,
+    dialogue_assets: Res<Assets<DialogueAsset>>,
 ) {
     let handle_ids = load_progress.handles.iter()
         .map(|handle| HandleId::from(handle));
+    let loaded = load_progress.handles.iter()
+        .filter(|handle| asset_server.get_load_state(*handle) == LoadState::Loaded)
+        .count();
+    let spawning = loading_map_query.iter().count();
+    load_progress.spawning_maps = load_progress.spawning_maps.max(spawning);
+    let total = load_progress.handles.len() + load_progress.spawning_maps;
+    let done = loaded + load_progress.spawning_maps - spawning;
+    load_progress.progress = if total == 0 { 1.0 } else { done as f32 / total as f32 };
+
     match asset_server.get_group_load_state(handle_ids) {
-        bevy::asset::LoadState::NotLoaded => {}
-        bevy::asset::LoadState::Loading => {}
-        bevy::asset::LoadState::Loaded => {
+        LoadState::NotLoaded => {}
+        LoadState::Loading => {}
+        LoadState::Loaded => {
+            // switch to the map's dialogue file
+            if let Some(handle) = &game_state.map_dialogue {
+                if let Some(asset) = dialogue_assets.get(handle) {
+                    for mut dialogue in dialogue_query.iter_mut() {
+                        if dialogue.handle != *handle {
+                            dialogue.replace_asset(handle.clone(), asset.clone());
+                        }
+                    }
+                }
+            }
             if let Some(node_name) = &load_progress.next_dialogue {
                 for mut dialogue in dialogue_query.iter_mut() {
                     dialogue.begin_optional(node_name.as_ref(), &mut dialogue_events);
                 }
             }
             // block transition if there are any complicated load objects still in the queue
-            if loading_map_query.iter().next().is_none() {
+            if spawning == 0 {
                 state.set(load_progress.next_state).expect("couldn't change state when assets finished loading");
+                if let Some((slot, data)) = load_progress.pending_save.take() {
+                    write_save_reporting(slot, &data);
+                }
                 load_progress.reset();
             }
         }
-        // TODO: Handle failed loading of assets, get rid of fs check in items.rs
-        bevy::asset::LoadState::Failed => {}
+        // handled by recover_failed_load_system
+        LoadState::Failed => {}
+    }
+}
+
+
+pub fn setup_loading_screen_runonce(
+    mut commands: Commands,
+    asset_server: Res<AssetServer>,
+    mut materials: ResMut<Assets<ColorMaterial>>,
+) {
+    let text_style = TextStyle {
+        font: asset_server.load("fonts/FiraSans-Bold.ttf"),
+        font_size: 32.0,
+        color: Color::rgb(0.9, 0.9, 0.9),
+    };
+    let hidden = Visible {
+        is_transparent: true,
+        is_visible: false,
+    };
+
+    commands.spawn_bundle(NodeBundle {
+        style: Style {
+            position_type: PositionType::Absolute,
+            size: Size::new(Val::Percent(100.0), Val::Percent(100.0)),
+            flex_direction: FlexDirection::ColumnReverse,
+            justify_content: JustifyContent::Center,
+            align_items: AlignItems::Center,
+            ..Default::default()
+        },
+        material: materials.add(Color::rgb(0.05, 0.05, 0.08).into()),
+        visible: hidden.clone(),
+        ..Default::default()
+    })
+    .insert(LoadingScreen {
+        shown_after: Timer::from_seconds(LOADING_SCREEN_DELAY, false),
+    })
+    .with_children(|parent| {
+        parent.spawn_bundle(TextBundle {
+            text: Text::with_section("Loading...", text_style, Default::default()),
+            style: Style {
+                margin: Rect {
+                    bottom: Val::Px(16.0),
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+            visible: hidden.clone(),
+            ..Default::default()
+        })
+        .insert(LoadingMapName);
+
+        // bar background with the fill inside
+        parent.spawn_bundle(NodeBundle {
+            style: Style {
+                size: Size::new(Val::Px(PROGRESS_BAR_WIDTH), Val::Px(16.0)),
+                ..Default::default()
+            },
+            material: materials.add(Color::rgb(0.2, 0.2, 0.25).into()),
+            visible: hidden.clone(),
+            ..Default::default()
+        })
+        .with_children(|parent| {
+            parent.spawn_bundle(NodeBundle {
+                style: Style {
+                    size: Size::new(Val::Percent(0.0), Val::Percent(100.0)),
+                    ..Default::default()
+                },
+                material: materials.add(Color::rgb(0.9, 0.75, 0.3).into()),
+                visible: hidden.clone(),
+                ..Default::default()
+            })
+            .insert(LoadingProgressBar);
+        });
+    });
+}
+
+pub fn loading_screen_system(
+    time: Res<Time>,
+    load_progress: Res<LoadProgress>,
+    game_state: Option<Res<Game>>,
+    asset_server: Res<AssetServer>,
+    mut screen_query: Query<(Entity, &mut LoadingScreen)>,
+    mut visible_query: Query<&mut Visible>,
+    children_query: Query<&Children>,
+    mut text_query: Query<&mut Text, With<LoadingMapName>>,
+    mut bar_query: Query<&mut Style, With<LoadingProgressBar>>,
+) {
+    for (entity, mut screen) in screen_query.iter_mut() {
+        if screen.shown_after.tick(time.delta()).just_finished() {
+            // show the whole screen
+            let mut entities = vec![entity];
+            while let Some(entity) = entities.pop() {
+                if let Ok(mut visible) = visible_query.get_mut(entity) {
+                    visible.is_visible = true;
+                }
+                if let Ok(children) = children_query.get(entity) {
+                    entities.extend(children.iter().cloned());
+                }
+            }
+        }
+    }
+
+    let map_name = game_state
+        .and_then(|game_state| map_path(&asset_server, &game_state.current_map))
+        .and_then(|path| Path::new(&path).file_stem().map(|stem| stem.to_string_lossy().into_owned()));
+    if let Some(map_name) = map_name {
+        for mut text in text_query.iter_mut() {
+            text.sections[0].value = format!("Loading {}...", map_name);
+        }
+    }
+    for mut style in bar_query.iter_mut() {
+        style.size.width = Val::Percent(load_progress.progress * 100.0);
     }
 }
 
+pub fn despawn_loading_screen_runonce(
+    mut commands: Commands,
+    screen_query: Query<Entity, With<LoadingScreen>>,
+) {
+    for entity in screen_query.iter() {
+        commands.entity(entity).despawn_recursive();
+    }
+}
 
 pub fn wait_for_map_ready_system(
     mut commands: Commands,
@@ -77,7 +254,8 @@ pub fn setup_map_objects_system(
     mut object_transform_query: Query<(&mut Transform, &Object)>,
     mut game_state: ResMut<Game>,
     mut event_reader: EventReader<ObjectReadyEvent>,
-    config: Res<Config>,
+    asset_server: Res<AssetServer>,
+    object_types: Res<ObjectTypeRegistry>,
     //mut map_container_query: Query<&mut MapContainer>,
 ) {
     for event in event_reader.iter() {
@@ -109,30 +287,79 @@ pub fn setup_map_objects_system(
             }
         }
         debug!("processing new entities {:?}", objects_to_process);
+        let map_path = map_path(&asset_server, &event.map_handle);
+        let parent_id = object_transform_query.get_mut(event.entity).ok()
+            .map(|(_, object)| object.id);
         for &(entity, is_child) in objects_to_process.iter() {
             if let Ok((object, mut visible)) = new_item_query.get_mut(entity) {
-                // check if objects already in scene, get default visibility
-                let previous_is_visible = game_state.entity_visibility.get(&entity);
-
-                if !previous_is_visible.is_some() {
-                    // set default visibility for when map transitions
-                    game_state
-                        .entity_visibility
-                        .insert(entity.clone(), object.visible && !object.is_shape() // default
-                    );
+                // IDs from the TMX stay the same when the map is respawned,
+                // unlike entities.
+                let object_id = map_path.as_ref().map(|path| ObjectId {
+                    map: path.clone(),
+                    parent: if is_child { parent_id } else { None },
+                    id: object.id,
+                });
+
+                // apply state persisted from a save
+                let (is_collected, is_revealed) = match &object_id {
+                    None => (false, false),
+                    Some(object_id) => (
+                        game_state.collected_objects.contains(object_id),
+                        game_state.revealed_objects.contains(object_id),
+                    ),
+                };
+                if is_collected {
+                    commands.entity(entity).despawn_recursive();
+                    if is_child {
+                        continue;
+                    }
+                    // the children were despawned with it
+                    break;
+                }
+
+                if let Some(object_id) = object_id {
+                    // set default visibility for when map transitions, unless
+                    // the object was seen before the map was respawned
+                    let is_visible = *game_state
+                        .object_visibility
+                        .entry(object_id.clone())
+                        .or_insert(is_revealed || (object.visible && !object.is_shape())); // default
+                    if event.map_handle == game_state.current_map {
+                        visible.is_visible = is_visible;
+                    }
+                    commands.entity(entity).insert(object_id);
                 }
-                // all objects from other maps (or according to last known) should spawn invisible
-                if event.map_handle != game_state.current_map { // || !is_visible {
+                // all objects from other maps should spawn invisible
+                if event.map_handle != game_state.current_map {
                     commands.entity(entity.clone()).remove::<Draw>();
                     visible.is_visible = false;
                 }
 
                 let mut behaviors: HashSet<ColliderBehavior> = Default::default();
+                let prefab_option = object_types.prefab(object);
 
                 let mut has_dialogue = false;
                 let mut auto_display_override = None;
                 let mut dialogue_spec = DialogueSpec::default();
-                for (k,v) in object.props.iter() {
+                let mut layers = CollisionLayers::DEFAULT;
+                let mut mask = CollisionLayers::ALL;
+                // the prefab's defaults come first so that properties override them
+                let mut props: Vec<(String, PropertyValue)> = Vec::new();
+                if let Some((prefab, _)) = prefab_option {
+                    let defaults = [
+                        ("dialogue", &prefab.dialogue),
+                        ("notice", &prefab.notice),
+                        ("layers", &prefab.layers),
+                        ("mask", &prefab.mask),
+                    ];
+                    for (k, v) in defaults.iter() {
+                        if let Some(v) = v {
+                            props.push((k.to_string(), PropertyValue::StringValue(v.clone())));
+                        }
+                    }
+                }
+                props.extend(object.props.iter().map(|(k, v)| (k.clone(), v.clone())));
+                for (k,v) in props.iter() {
                     if k == "dialogue" {
                         if let PropertyValue::StringValue(s) = v {
                             has_dialogue = true;
@@ -151,6 +378,14 @@ pub fn setup_map_objects_system(
                         if let PropertyValue::BoolValue(b) = v {
                             auto_display_override = Some(*b);
                         }
+                    } else if k == "layers" || k == "mask" {
+                        if let PropertyValue::StringValue(s) = v {
+                            match CollisionLayers::parse(s) {
+                                Ok(parsed) if k == "layers" => layers = parsed,
+                                Ok(parsed) => mask = parsed,
+                                Err(err) => eprintln!("Warning: object {} has bad {} property: {:?}", object.id, k, err),
+                            }
+                        }
                     }
                 }
                 if has_dialogue {
@@ -167,48 +402,69 @@ pub fn setup_map_objects_system(
                     behaviors.insert(ColliderBehavior::Dialogue(dialogue_spec));
                 }
 
-                // we should have actual types based on object name
-                // and add components based on that
-                match object.name.as_ref() {
-                    "spawn" | "trigger" => {}
-                    "biggem" | "gem" => {
-                        if object.visible {
-                            behaviors.insert(ColliderBehavior::Collect);
+                // the kind of object determines what else it does
+                match prefab_option {
+                    Some((prefab, argument)) => {
+                        if prefab.debug {
+                            commands.entity(entity).insert(Debuggable::default());
                         }
-                    },
-                    _ => {
-                        if object.name.starts_with("load:") {
-                            if object.visible {
-                                behaviors.insert(ColliderBehavior::Load { path: object.name[5..].to_string() });
-                            }
-                        } else {
-                            if object.is_shape() { // allow hide/show objects without images
-                                commands.entity(entity).insert(Debuggable::default());
-                                behaviors.insert(ColliderBehavior::Obstruct);
-                            } else if !has_kids {
-                                // sprites with no inner objects fully obstruct
-                                // we may opt to change this behavior eventually
-                                behaviors.insert(ColliderBehavior::Obstruct);
-                            }
+                        if object.visible || !prefab.requires_visible {
+                            behaviors.extend(prefab.collider_behaviors(object, argument));
+                        }
+                        object_types.insert_components(&mut commands.entity(entity), object);
+                    }
+                    None => {
+                        if object.is_shape() { // allow hide/show objects without images
+                            commands.entity(entity).insert(Debuggable::default());
+                            behaviors.insert(ColliderBehavior::Obstruct);
+                        } else if !has_kids {
+                            // sprites with no inner objects fully obstruct
+                            // we may opt to change this behavior eventually
+                            behaviors.insert(ColliderBehavior::Obstruct);
                         }
                     }
                 }
 
-                let mut collider_size = config.map_scale * match object.shape {
-                    ObjectShape::Rect { width, height } | ObjectShape::Ellipse { width, height } =>
-                        Vec2::new(width, height),
-                    ObjectShape::Polyline { points: _ } | ObjectShape::Polygon { points: _ } | ObjectShape::Point(_, _) =>
-                        Vec2::new(40.0, 40.0),
-                };
-                if is_child {
-                    let parent = object_transform_query.get_mut(event.entity).expect("Need object for child scale").1;
-                    let parent_scale = parent.tile_scale.expect("Need valid scale for child collider");
-                    collider_size *= parent_scale;
-                }
+                // map scale and the scale of parent tiles come from the transform
+                let shape = object_collider_shape(object);
 
-                let collider_component = Collider::new(behaviors, collider_size, Vec2::new(0.0, 0.0));
+                let mut collider_component = Collider::with_shape(behaviors, shape, Vec2::new(0.0, 0.0))
+                    .with_layers(layers, mask);
+                if is_revealed {
+                    reveal_collider(object, &mut collider_component, &object_types);
+                }
                 commands.entity(entity).insert(collider_component);
             }
         }
     }
 }
+
+// Colliders follow the object's shape as it was drawn in Tiled.
+pub fn object_collider_shape(object: &Object) -> ColliderShape {
+    match &object.shape {
+        ObjectShape::Rect { width, height } =>
+            ColliderShape::rect(Vec2::new(*width, *height)),
+        ObjectShape::Ellipse { width, height } =>
+            ColliderShape::ellipse(Vec2::new(*width, *height)),
+        ObjectShape::Polygon { points } => {
+            let points = object_local_points(object, points);
+            ColliderShape::convex_polygon(&points)
+                .unwrap_or_else(|| ColliderShape::polyline(&points))
+        }
+        ObjectShape::Polyline { points } =>
+            ColliderShape::polyline(&object_local_points(object, points)),
+        // points have no area, so keep the size of their debug box
+        ObjectShape::Point(_, _) =>
+            ColliderShape::rect(object.size),
+    }
+}
+
+// Tiled gives points relative to the object's position with y pointing down.
+// The object's entity is centered on its size box, which starts at that
+// position.
+fn object_local_points(object: &Object, points: &[(f32, f32)]) -> Vec<Vec2> {
+    let center = object.size / 2.0;
+    points.iter()
+        .map(|&(x, y)| Vec2::new(x - center.x, center.y - y))
+        .collect()
+}
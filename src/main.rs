@@ -15,7 +15,10 @@ mod scene2d;
 mod items;
 mod loading;
 mod motion;
+mod objects;
 mod players;
+mod saving;
+mod transition;
 mod ui; // in-game ui
 
 use loading::LoadProgress;
@@ -32,10 +35,18 @@ const DEBUG_MODE_DEFAULT: bool = false;
 
 fn main() -> Result<()> {
     let config = core::config::load_asset_config("app.toml")?;
+    let mut object_types = objects::load_object_types(config.object_types.as_path())?;
+    scene2d::register_object_components(&mut object_types);
+    let sound_table = core::audio::load_sound_table(config.sounds.as_path())?;
+    let window_descriptor = config.settings.window_descriptor(&config.title);
 
     App::build()
+        .insert_resource(window_descriptor)
         .insert_resource(config)
+        .insert_resource(object_types)
+        .insert_resource(sound_table)
         .insert_resource(LoadProgress::default())
+        .insert_resource(saving::LoadedSave::default())
         .add_event::<motion::MoveEntityEvent<Player>>()
         .add_state(AppState::default())
         // add stages to run loop
@@ -45,47 +56,77 @@ fn main() -> Result<()> {
         .add_plugins(DefaultPlugins)
         .add_plugin(TiledMapPlugin)
         // add our plugins
+        .add_plugin(core::audio::GameAudioPlugin::default())
+        .add_plugin(core::broadphase::BroadphasePlugin::default())
         .add_plugin(core::menu::MenuPlugin::default())
+        .add_plugin(core::pause::PausePlugin::default())
+        .add_plugin(core::settings::SettingsPlugin::default())
         .add_plugin(core::dialogue::DialoguePlugin::default())
         .add_plugin(core::input::InputActionPlugin::default())
         .add_plugin(items::ItemsPlugin::default())
+        .add_plugin(transition::TransitionPlugin::default())
         // initialization
         .add_startup_system_to_stage(Startup, setup_onboot.system())
         .add_startup_system_to_stage(Later, scene2d::initialize_levels_onboot.system())
         // run in all states:
-        .add_system_to_stage(Update, bevy::input::system::exit_on_esc_system.system())
         .add_system_to_stage(Update, motion::instant_move_player_system.system())
+        .add_system_to_stage(Update, ui::localize_dialogue_system.system())
         .add_system_to_stage(PreUpdate, loading::setup_map_objects_system.system())
         // -- why is "preupdate" required here ^ ? Without it, there's an intermittent bug where colliders aren't added
 
         // loading
         .add_system_set(SystemSet::on_update(AppState::Loading)
             .with_system(loading::wait_for_map_ready_system.system().before("main")) // this just removes Complicated tag
+            .with_system(scene2d::spawn_loaded_maps_system.system().before("main"))
+            .with_system(scene2d::update_map_settings_system.system().before("main"))
             .with_system(loading::wait_for_asset_loading_system.system().label("main"))
             .with_system(scene2d::create_tile_objects_system.system().after("main"))
-        ).add_system_set(SystemSet::on_exit(AppState::Loading).with_system(scene2d::hide_non_map_objects_runonce.system()))
+            .with_system(loading::loading_screen_system.system().after("main"))
+            .with_system(scene2d::recover_failed_load_system.system().after("main"))
+        ).add_system_set(SystemSet::on_enter(AppState::Loading).with_system(loading::setup_loading_screen_runonce.system()))
+        .add_system_set(SystemSet::on_exit(AppState::Loading)
+            .with_system(loading::despawn_loading_screen_runonce.system())
+            .with_system(scene2d::hide_non_map_objects_runonce.system())
+            .with_system(scene2d::evict_maps_runonce.system()))
 
         // menu
         .add_system_set(SystemSet::on_update(AppState::Menu)
             .with_system(core::menu::menu_system.system()
                 // TODO: run these once using stages
+                .chain(saving::start_game_runonce.system())
                 .chain(players::setup_players_runonce.system())
                 .chain(ui::setup_dialogue_window_runonce.system())
             )
         )
 
+        // paused
+        .add_system_set(SystemSet::on_update(AppState::Paused)
+            .with_system(core::pause::pause_menu_system.system()
+                .chain(saving::save_game_runonce.system())
+                .chain(scene2d::return_to_title_runonce.system())
+            )
+        )
+
         // in-game:
         .add_system_set(SystemSet::on_enter(AppState::InGame).with_system(scene2d::show_map_and_objects_runonce.system()))
+        .add_system_set(SystemSet::on_enter(AppState::InGame).with_system(scene2d::apply_map_settings_runonce.system()))
         .add_system_set(SystemSet::on_enter(AppState::InGame).with_system(scene2d::in_game_start_runonce.system()))
         .add_system_set(SystemSet::on_update(AppState::InGame)
             .with_system(actions::handle_movement_input_system.system()
                 .label("early"))
             .with_system(actions::handle_dialogue_input_system.system())
+            .with_system(actions::interaction_probe_system.system()
+                .label("probe")
+                .after("early"))
+            .with_system(actions::interaction_prompt_system.system().after("probe"))
             .with_system(camera::update_camera_system.system().after("early"))
             .with_system(debug::position_display_system.system().after("early"))
+            .with_system(debug::map_stats_display_system.system())
+            .with_system(scene2d::preload_adjacent_maps_system.system())
             .with_system(motion::animate_sprite_system.system().after("early"))
             .with_system(motion::continous_move_character_system.system().after("early"))
             .with_system(ui::display_dialogue_system.system().after("early"))
+            .with_system(ui::reveal_dialogue_text_system.system().after("early"))
         )
         .run();
 
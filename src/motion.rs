@@ -4,17 +4,19 @@ use std::convert::TryFrom;
 use crate::{
     actions::DialogueActor,
     core::{
+        audio::SoundEvent,
+        broadphase::CollisionBroadphase,
         character::{AnimatedSprite, Character, CharacterState, Direction},
         collider::{Collider, ColliderBehavior, Collision},
         config::Config,
         game::{DialogueSpec, Game},
     },
-    items::ItemInteraction,
+    items::{InteractionPhase, ItemInteraction},
     players::Player,
 };
 
-use bevy::{ecs::component::Component, prelude::*, utils::HashSet};
-use bevy_tiled_prototype::Map;
+use bevy::{ecs::component::Component, prelude::*, utils::{HashMap, HashSet}};
+use parry2d::bounding_volume::BoundingVolume;
 
 
 // If two scalars have an absolute value difference less than this, then they're
@@ -30,11 +32,13 @@ pub fn z_from_y(y: f32) -> f32 {
 pub struct MoveEntityEvent<T: Component> {
     pub object_component: PhantomData<T>,
     pub target: Entity,
+    // Direction that moved characters should face.
+    pub direction: Option<Direction>,
 }
 
 pub fn instant_move_player_system(
     events: EventReader<MoveEntityEvent<Player>>,
-    query: Query<(&mut Transform, Option<&Player>)>,
+    query: Query<(&mut Transform, Option<&Player>, Option<&mut Character>)>,
     config: Res<Config>,
 ) {
     instant_move_entity(events, query, Vec3::new(2.2 * config.char_width, 0.0, 0.0));
@@ -43,21 +47,24 @@ pub fn instant_move_player_system(
 // Currently used for warping between levels, but could be useful for many other things
 fn instant_move_entity<T: Component>(
     mut events: EventReader<MoveEntityEvent<T>>,
-    mut query: Query<(&mut Transform, Option<&T>)>,
+    mut query: Query<(&mut Transform, Option<&T>, Option<&mut Character>)>,
     offset: Vec3, // additive
 ) {
     for event in events.iter() {
         let target = match query.get_mut(event.target) {
-            Ok((transform, _)) => transform.translation,
+            Ok((transform, _, _)) => transform.translation,
             Err(_) => continue,
         };
         let mut total_offset = Vec3::ZERO;
-        for (mut transform, has_component) in query.iter_mut() {
+        for (mut transform, has_component, character_option) in query.iter_mut() {
             if has_component.is_none() {
                 continue;
             }
             transform.translation = target + total_offset;
             total_offset += offset;
+            if let (Some(direction), Some(mut character)) = (event.direction, character_option) {
+                character.direction = direction;
+            }
         }
     }
 }
@@ -65,7 +72,8 @@ fn instant_move_entity<T: Component>(
 pub fn animate_sprite_system(
     time: Res<Time>,
     texture_atlases: Res<Assets<TextureAtlas>>,
-    mut query: Query<(&mut TextureAtlasSprite, &Handle<TextureAtlas>, &mut AnimatedSprite, Option<&Character>)>
+    mut query: Query<(&mut TextureAtlasSprite, &Handle<TextureAtlas>, &mut AnimatedSprite, Option<&Character>)>,
+    mut sound_events: EventWriter<SoundEvent>,
 ) {
     for (mut sprite, texture_atlas_handle, mut animated_sprite, character_option) in query.iter_mut() {
         // If character just started walking or is colliding, always show
@@ -118,6 +126,11 @@ pub fn animate_sprite_system(
                 animated_sprite.animation_index + 1
             };
             new_anim_index = new_anim_index % num_cells_in_animation;
+            // feet touch the ground on even frames of walking and running
+            let is_moving = character_option.map_or(false, |ch| ch.state() != CharacterState::Idle);
+            if is_moving && new_anim_index != animated_sprite.animation_index && new_anim_index % 2 == 0 {
+                sound_events.send(SoundEvent::new("footstep"));
+            }
             animated_sprite.animation_index = new_anim_index;
             sprite.index = ((start_index + new_anim_index as usize) % total_num_cells) as u32;
         }
@@ -125,88 +138,203 @@ pub fn animate_sprite_system(
 }
 
 // This system applies a velocity and checks for collisions.
-// If the collision is obstructing, it stops movement
+// If the collision is obstructing, it tries moving along each axis alone so
+// that characters slide along walls.  Fast movement is split into steps so
+// that characters can't skip over thin walls.
+//
+// Overlaps are tracked per character and collider, including for characters
+// that stand still, so that interactions have Enter, Stay and Exit phases.
 pub fn continous_move_character_system(
     time: Res<Time>,
     mut interaction_event: EventWriter<ItemInteraction>,
+    mut overlaps: Local<HashMap<(Entity, Entity), HashSet<ColliderBehavior>>>,
     mut char_query: Query<(Entity, &mut Character, Option<&mut DialogueActor>, &mut Transform, &GlobalTransform)>,
     game_state: Res<Game>,
-    mut collider_query: Query<(Entity, &mut Collider, &GlobalTransform, Option<&Parent>)>,
-    object_children_query: Query<&Handle<Map>>,
+    broadphase: Res<CollisionBroadphase>,
+    collider_query: Query<(&Collider, &GlobalTransform)>,
 ) {
+    let mut previous_overlaps = std::mem::take(&mut *overlaps);
     for (char_entity, mut character, dialogue_actor_option, mut transform, char_global) in char_query.iter_mut() {
         let char_collider = collider_query.get_component::<Collider>(char_entity).unwrap().clone();
-        if character.velocity.abs_diff_eq(Vec2::ZERO, VELOCITY_EPSILON) {
-            // Character has zero velocity.  Nothing to do.
-            continue;
-        }
-        let delta: Vec2 = character.velocity * time.delta_seconds() * character.movement_speed;
+        // A character with zero velocity only checks where it is.
+        let is_still = character.velocity.abs_diff_eq(Vec2::ZERO, VELOCITY_EPSILON);
+        let delta: Vec2 = if is_still {
+            Vec2::ZERO
+        } else {
+            character.velocity * time.delta_seconds() * character.movement_speed
+        };
 
-        // check for collisions with objects in current map
-        let char_aabb = char_collider.bounding_volume_with_translation(char_global, delta);
-        let mut char_collision = Collision::empty();
-        let mut dialogue_collision = None;
+        // Steps no longer than half the character's collider can't pass
+        // through anything without touching it.
+        let half_extents = char_collider.bounding_volume(char_global).half_extents();
+        let max_step = half_extents.x.min(half_extents.y).max(1.0);
+        let num_steps = (delta.length() / max_step).ceil().max(1.0);
+        let step = delta / num_steps;
 
-        for (collider_entity, collider, collider_global, maybe_parent) in collider_query.iter_mut() {
-            // TODO: Use the entity instead of the map asset handle in case
-            // In theory,  there can be multiple instances of the same map.
+        // Only colliders near the path in the current map can be touched.
+        let path_aabb = char_collider.bounding_volume(char_global)
+            .merged(&char_collider.bounding_volume_with_translation(char_global, delta));
+        let nearby = broadphase.query(&game_state.current_map, &path_aabb);
 
-            if let Ok(owner_map) = object_children_query.get(collider_entity.clone())  {
-                if *owner_map != game_state.current_map {
-                    continue;
-                }
-            }
-            if let Some(parent) = maybe_parent {
-                if let Ok(owner_map) = object_children_query.get(parent.0.clone())  {
-                    if *owner_map != game_state.current_map {
-                        continue;
-                    }
-                }
+        let collisions_at = |delta: Vec2| collisions_with_character(
+            char_entity,
+            &char_collider,
+            char_global,
+            delta,
+            &nearby,
+            &collider_query,
+        );
+        let (moved, mut contacts) = if is_still {
+            (Vec2::ZERO, collisions_at(Vec2::ZERO))
+        } else {
+            slide(step, num_steps as usize, collisions_at)
+        };
+        let mut char_collision = Collision::empty();
+        for (_, collision) in contacts.iter() {
+            for behavior in collision.behaviors.iter() {
+                char_collision.insert_behavior(behavior.clone());
             }
+        }
 
-            // Shouldn't collide with itself.
-            if collider_entity == char_entity {
+        // A blocked step stops up to a step short of what blocked it, so
+        // obstructions stay touched while the character is that close.
+        // Otherwise resting against a wall would exit and enter on every push.
+        let previous_obstructions: Vec<Entity> = previous_overlaps.iter()
+            .filter(|((entity, _), behaviors)| *entity == char_entity && behaviors.contains(&ColliderBehavior::Obstruct))
+            .map(|((_, collider_entity), _)| *collider_entity)
+            .collect();
+        for collider_entity in previous_obstructions {
+            if contacts.iter().any(|(entity, _)| *entity == collider_entity) {
                 continue;
             }
-            match collider.intersect(collider_global, &char_aabb) {
-                None => {}
-                Some(collision) => {
-                    for behavior in collision.behaviors.iter() {
-                        char_collision.insert_behavior(behavior.clone());
-                    }
-
-                    dialogue_collision = dialogue_collision.or_else(||
-                        dialogue_behavior(&collision.behaviors)
-                    );
-                    interaction_event.send(ItemInteraction::new(
-                        char_entity,
-                        collider_entity,
-                        collision.behaviors,
-                    ));
+            if let Ok((collider, collider_global)) = collider_query.get(collider_entity) {
+                if collider.behaviors.contains(&ColliderBehavior::Obstruct)
+                    && collider.is_near(collider_global, &char_collider, char_global, moved, max_step) {
+                    contacts.push((collider_entity, Collision {
+                        behaviors: collider.behaviors.clone(),
+                    }));
                 }
             }
         }
-        if !char_collision.is_obstruction() {
-            transform.translation.x += delta.x;
-            transform.translation.y += delta.y;
+
+        let mut dialogue_collision = None;
+        for (collider_entity, collision) in contacts {
+            dialogue_collision = dialogue_collision.or_else(||
+                dialogue_behavior(&collision.behaviors)
+            );
+            let pair = (char_entity, collider_entity);
+            let phase = match previous_overlaps.remove(&pair) {
+                None => InteractionPhase::Enter,
+                Some(_) => InteractionPhase::Stay,
+            };
+            overlaps.insert(pair, collision.behaviors.clone());
+            interaction_event.send(ItemInteraction::new(
+                char_entity,
+                collider_entity,
+                collision.behaviors,
+                phase,
+            ));
+        }
+        if moved != Vec2::ZERO {
+            transform.translation.x += moved.x;
+            transform.translation.y += moved.y;
             // Z needs to reflect where the character is on the ground, and
             // presumably, that's where the character collides.  So we add the
             // collider's Z offset to the translation.
-            transform.translation.z = z_from_y(transform.translation.y + char_collider.offset.y);
+            transform.translation.z = z_from_y(transform.translation.y + char_collider.world_offset(char_global).y);
         }
         character.collision = char_collision;
         if let Some(mut dialogue_actor) = dialogue_actor_option {
             dialogue_actor.collider_dialogue = dialogue_collision;
         }
     }
+
+    // Whatever is left was touched last frame but not this one.
+    for ((char_entity, collider_entity), behaviors) in previous_overlaps {
+        interaction_event.send(ItemInteraction::new(
+            char_entity,
+            collider_entity,
+            behaviors,
+            InteractionPhase::Exit,
+        ));
+    }
+}
+
+// Move by up to num_steps steps, trying each axis alone when a step is
+// obstructed.  Returns how far it moved and what it touched: everything where
+// it moved to, and only the obstructions where it couldn't move.  Otherwise
+// it could collect or load things it never reached.
+fn slide(
+    step: Vec2,
+    num_steps: usize,
+    mut collisions_at: impl FnMut(Vec2) -> Vec<(Entity, Collision)>,
+) -> (Vec2, Vec<(Entity, Collision)>) {
+    let mut contacts: Vec<(Entity, Collision)> = Vec::new();
+    let mut moved = Vec2::ZERO;
+    for _ in 0..num_steps {
+        let mut has_moved = false;
+        for &attempt in [step, Vec2::new(step.x, 0.0), Vec2::new(0.0, step.y)].iter() {
+            if attempt == Vec2::ZERO {
+                continue;
+            }
+            let collisions = collisions_at(moved + attempt);
+            let is_obstructed = collisions.iter().any(|(_, collision)| collision.is_obstruction());
+            for (collider_entity, collision) in collisions {
+                if is_obstructed && !collision.is_obstruction() {
+                    continue;
+                }
+                if !contacts.iter().any(|(entity, _)| *entity == collider_entity) {
+                    contacts.push((collider_entity, collision));
+                }
+            }
+            if !is_obstructed {
+                moved += attempt;
+                has_moved = true;
+                break;
+            }
+        }
+        if !has_moved {
+            break;
+        }
+    }
+
+    (moved, contacts)
+}
+
+// Colliders among the nearby ones that the character would touch after moving
+// by delta.
+fn collisions_with_character(
+    char_entity: Entity,
+    char_collider: &Collider,
+    char_global: &GlobalTransform,
+    delta: Vec2,
+    nearby: &[Entity],
+    collider_query: &Query<(&Collider, &GlobalTransform)>,
+) -> Vec<(Entity, Collision)> {
+    let mut collisions = Vec::new();
+    for &collider_entity in nearby.iter() {
+        // Shouldn't collide with itself.
+        if collider_entity == char_entity {
+            continue;
+        }
+        if let Ok((collider, collider_global)) = collider_query.get(collider_entity) {
+            if let Some(collision) = collider.intersect(collider_global, char_collider, char_global, delta) {
+                collisions.push((collider_entity, collision));
+            }
+        }
+    }
+
+    collisions
 }
 
-fn dialogue_behavior(behaviors: &HashSet<ColliderBehavior>) -> Option<DialogueSpec> {
+// Dialogue that begins when the player presses Accept.
+pub fn dialogue_behavior(behaviors: &HashSet<ColliderBehavior>) -> Option<DialogueSpec> {
     for behavior in behaviors.iter() {
         match behavior {
             ColliderBehavior::Obstruct => {}
             ColliderBehavior::Collect => {}
             ColliderBehavior::Load { path: _ } => {}
+            ColliderBehavior::Checkpoint => {}
             ColliderBehavior::Dialogue(spec) => {
                 // If it should be auto-displayed, another system already
                 // displays it.
@@ -219,3 +347,54 @@ fn dialogue_behavior(behaviors: &HashSet<ColliderBehavior>) -> Option<DialogueSp
 
     None
 }
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn collision(behavior: ColliderBehavior) -> Collision {
+        let mut collision = Collision::empty();
+        collision.insert_behavior(behavior);
+
+        collision
+    }
+
+    #[test]
+    fn rejected_slide_attempts_only_touch_obstructions() {
+        let wall = Entity::new(1);
+        let gem = Entity::new(2);
+        // The diagonal step hits the wall and the gem, but moving along x
+        // alone is clear.
+        let (moved, contacts) = slide(Vec2::new(1.0, 1.0), 1, |delta| {
+            if delta.y > 0.0 {
+                vec![
+                    (wall, collision(ColliderBehavior::Obstruct)),
+                    (gem, collision(ColliderBehavior::Collect)),
+                ]
+            } else {
+                Vec::new()
+            }
+        });
+
+        assert_eq!(moved, Vec2::new(1.0, 0.0));
+        let touched: Vec<Entity> = contacts.iter().map(|(entity, _)| *entity).collect();
+        assert_eq!(touched, vec![wall]);
+    }
+
+    #[test]
+    fn accepted_slide_attempts_touch_everything() {
+        let gem = Entity::new(2);
+        let (moved, contacts) = slide(Vec2::new(1.0, 0.0), 2, |delta| {
+            if delta.x > 1.5 {
+                vec![(gem, collision(ColliderBehavior::Collect))]
+            } else {
+                Vec::new()
+            }
+        });
+
+        assert_eq!(moved, Vec2::new(2.0, 0.0));
+        assert_eq!(contacts.len(), 1);
+        assert_eq!(contacts[0].0, gem);
+    }
+}
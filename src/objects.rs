@@ -0,0 +1,182 @@
+use std::collections::HashMap as StdHashMap;
+use std::fs;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use bevy::{asset::FileAssetIo, ecs::system::EntityCommands, utils::HashMap};
+use bevy_tiled_prototype::{Object, PropertyValue};
+use serde::Deserialize;
+
+use crate::core::{collider::ColliderBehavior, game::{DialogueSpec, DialogueUiType}};
+
+// Inserts components for a kind of object.  Registered from Rust.
+pub type ComponentInserter = fn(&mut EntityCommands, &Object);
+
+// What kinds of map objects are, loaded from a RON file in the assets.  An
+// object's kind is its type in Tiled, with its name as an argument.  Objects
+// without a type use their name, and anything after a colon is the argument,
+// e.g. the map of "load:ritualspace".
+#[derive(Default)]
+pub struct ObjectTypeRegistry {
+    prefabs: HashMap<String, ObjectPrefab>,
+    inserters: HashMap<String, Vec<ComponentInserter>>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct ObjectPrefab {
+    pub behaviors: Vec<PrefabBehavior>,
+    // Hidden objects only get their behaviors once they're revealed.
+    pub requires_visible: bool,
+    // Gems added to the inventory when collected.
+    pub value: u32,
+    // Sound played when collected, from the sound table.
+    pub sfx: Option<String>,
+    // Dialogue nodes to use when the object doesn't have a dialogue or notice
+    // property.
+    pub dialogue: Option<String>,
+    pub notice: Option<String>,
+    // Collision layer names, when the object doesn't have the properties.
+    pub layers: Option<String>,
+    pub mask: Option<String>,
+    // Only shown in debug mode.
+    pub debug: bool,
+    // How hidden objects of this kind are revealed.  Others stay hidden.
+    pub reveal: Option<RevealSpec>,
+}
+
+// When a hidden object appears.  It then has its kind's behaviors instead of
+// any it had while hidden.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct RevealSpec {
+    // Gems that players need between them.
+    pub gems: u32,
+    // Players' gems are used up when it's revealed.
+    pub spend_gems: bool,
+    // Only objects in the map that players are in are revealed.
+    pub current_map_only: bool,
+    // Notice shown when it's touched after being revealed.
+    pub notice: Option<String>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub enum PrefabBehavior {
+    Obstruct,
+    Collect,
+    Checkpoint,
+    // Loads the map named by the argument.
+    Load,
+}
+
+#[derive(Debug, Deserialize)]
+struct ObjectTypesFile {
+    kinds: StdHashMap<String, ObjectPrefab>,
+}
+
+impl ObjectTypeRegistry {
+    pub fn register(&mut self, kind: &str, prefab: ObjectPrefab) {
+        self.prefabs.insert(kind.to_string(), prefab);
+    }
+
+    // Components to add to every object of the kind, for things that can't
+    // be described in the file.
+    pub fn add_components(&mut self, kind: &str, inserter: ComponentInserter) {
+        self.inserters.entry(kind.to_string()).or_insert_with(Vec::new).push(inserter);
+    }
+
+    // The object's prefab and the argument from its name.
+    pub fn prefab<'a>(&self, object: &'a Object) -> Option<(&ObjectPrefab, Option<&'a str>)> {
+        let (kind, argument) = object_kind(object);
+
+        self.prefabs.get(kind).map(|prefab| (prefab, argument))
+    }
+
+    // Collider behaviors of a revealed object, or None if its kind can't be
+    // revealed.
+    pub fn revealed_behaviors(&self, object: &Object) -> Option<Vec<ColliderBehavior>> {
+        let (prefab, argument) = self.prefab(object)?;
+        let reveal = prefab.reveal.as_ref()?;
+        let mut behaviors = prefab.collider_behaviors(object, argument);
+        if let Some(node_name) = &reveal.notice {
+            behaviors.push(ColliderBehavior::Dialogue(DialogueSpec {
+                node_name: node_name.clone(),
+                ui_type: DialogueUiType::Notice,
+                auto_display: true,
+            }));
+        }
+
+        Some(behaviors)
+    }
+
+    pub fn insert_components(&self, entity_commands: &mut EntityCommands, object: &Object) {
+        let (kind, _) = object_kind(object);
+        if let Some(inserters) = self.inserters.get(kind) {
+            for inserter in inserters.iter() {
+                inserter(entity_commands, object);
+            }
+        }
+    }
+}
+
+impl ObjectPrefab {
+    // Collider behaviors of an object of this kind, given the argument from
+    // its name.
+    pub fn collider_behaviors(&self, object: &Object, argument: Option<&str>) -> Vec<ColliderBehavior> {
+        self.behaviors.iter()
+            .filter_map(|behavior| match behavior {
+                PrefabBehavior::Obstruct => Some(ColliderBehavior::Obstruct),
+                PrefabBehavior::Collect => Some(ColliderBehavior::Collect),
+                PrefabBehavior::Checkpoint => Some(ColliderBehavior::Checkpoint),
+                PrefabBehavior::Load => match argument {
+                    Some(path) => Some(ColliderBehavior::Load { path: path.to_string() }),
+                    None => {
+                        eprintln!("Warning: object {} needs a map to load after a colon in its name", object.id);
+                        None
+                    }
+                },
+            })
+            .collect()
+    }
+}
+
+// The kind of the object and the argument from its name.
+pub fn object_kind(object: &Object) -> (&str, Option<&str>) {
+    // with a type, the whole name is the argument
+    let argument = Some(object.name.as_str()).filter(|name| !name.is_empty());
+    if !object.obj_type.is_empty() {
+        return (object.obj_type.as_str(), argument);
+    }
+    // newer versions of Tiled save the type as a class, which isn't read
+    if let Some(PropertyValue::StringValue(kind)) = object.props.get("type") {
+        return (kind.as_str(), argument);
+    }
+    match object.name.find(':') {
+        None => (object.name.as_str(), None),
+        Some(index) => (&object.name[..index], Some(&object.name[index + 1..])),
+    }
+}
+
+pub fn load_object_types(path: &Path) -> Result<ObjectTypeRegistry> {
+    let mut asset_path = FileAssetIo::get_root_path();
+    asset_path.push("assets");
+    asset_path.push(path);
+
+    let contents = fs::read_to_string(asset_path.as_path())
+        .with_context(||
+            format!("error reading object types file: {:?}",
+                    asset_path.as_os_str())
+        )?;
+    let file: ObjectTypesFile = ron::de::from_str(contents.as_ref())
+        .with_context(||
+            format!("error parsing object types file: {:?}",
+                    asset_path.as_os_str())
+        )?;
+
+    let mut registry = ObjectTypeRegistry::default();
+    for (kind, prefab) in file.kinds {
+        registry.register(&kind, prefab);
+    }
+
+    Ok(registry)
+}
@@ -1,6 +1,6 @@
 use bevy::{math::Vec3Swizzles, prelude::*};
 
-use crate::{DEBUG_MODE_DEFAULT, actions::DialogueActor, core::{character::{AnimatedSprite, Character}, collider::{Collider, ColliderBehavior}, config::Config, menu::MenuAction, state::TransientState}, debug::{PlayerPositionDisplay, Debuggable}, items::Inventory, loading::LoadProgress, motion::z_from_y};
+use crate::{DEBUG_MODE_DEFAULT, actions::{DialogueActor, InteractionPrompt}, core::{character::{AnimatedSprite, Character}, collider::{Collider, ColliderBehavior, CollisionLayers}, config::Config, menu::MenuAction, state::TransientState}, debug::{MapStatsDisplay, PlayerPositionDisplay, Debuggable}, items::Inventory, loading::LoadProgress, motion::z_from_y, saving::LoadedSave};
 
 
 pub struct Player {
@@ -18,9 +18,10 @@ pub fn setup_players_runonce(
     transient_state: Res<TransientState>,
     mut to_load: ResMut<LoadProgress>,
     config: Res<Config>,
+    loaded_save: Res<LoadedSave>,
 ) -> MenuAction {
     let num_players = match menu_action {
-        MenuAction::Nil => return menu_action,
+        MenuAction::Nil | MenuAction::Continue => return menu_action,
         MenuAction::LoadPlayers { num_players } => num_players,
     };
 
@@ -40,13 +41,29 @@ pub fn setup_players_runonce(
         let collider_offset = Vec2::new(0.0, -12.5);
         // This should match the move_character_system.
         let initial_z = z_from_y(collider_offset.y);
+        let player_save = loaded_save.0.as_ref()
+            .and_then(|data| data.players.iter().find(|p| p.id == u32::from(i)));
+        let transform = match player_save {
+            None => Transform::from_scale(scale)
+                .mul_transform(Transform::from_translation(
+                    Vec3::new(config.char_width * i as f32 + 20.0, 0.0, initial_z))),
+            Some(player_save) => {
+                let (x, y) = player_save.position;
+                Transform {
+                    translation: Vec3::new(x, y, z_from_y(y + collider_offset.y * scale.y)),
+                    scale,
+                    ..Default::default()
+                }
+            }
+        };
+        let inventory = Inventory {
+            num_gems: player_save.map_or(0, |p| p.num_gems),
+        };
         commands
             .spawn()
             .insert_bundle(SpriteSheetBundle {
                 texture_atlas: texture_atlas_handle,
-                transform: Transform::from_scale(scale)
-                    .mul_transform(Transform::from_translation(
-                        Vec3::new(config.char_width * i as f32 + 20.0, 0.0, initial_z))),
+                transform,
                 ..Default::default()
             })
             .insert_bundle((
@@ -57,13 +74,14 @@ pub fn setup_players_runonce(
                     height: config.char_height,
                     width: config.char_width,
                 },
-                Inventory::default(),
+                inventory,
                 DialogueActor::default(),
+                // Scaled along with the character.
                 Collider::single(
                     ColliderBehavior::Obstruct,
-                    collider_size * scale.xy(),
-                    collider_offset * scale.xy(),
-                ),
+                    collider_size,
+                    collider_offset,
+                ).with_layers(CollisionLayers::PLAYER, CollisionLayers::ALL),
             ))
             .with_children(|parent| {
                 // add a shadow sprite -- is there a more efficient way where we load this just once??
@@ -77,6 +95,19 @@ pub fn setup_players_runonce(
                     material: materials.add(shadow_handle.into()),
                     ..Default::default()
                 });
+                // shown above whatever the player can talk to
+                let prompt_handle = to_load.add(asset_server.load("sprites/prompt.png"));
+                parent.spawn_bundle(SpriteBundle {
+                    transform: Transform::from_translation(Vec3::new(0.0, 0.0, 10.0)),
+                    material: materials.add(prompt_handle.into()),
+                    visible: Visible {
+                        is_transparent: true,
+                        is_visible: false,
+                        ..Default::default()
+                    },
+                    ..Default::default()
+                })
+                .insert(InteractionPrompt);
                 // collider debug indicator - TODO: refactor into Collider::new_with_debug(parent, collider_size, scale)
                 parent.spawn_bundle(SpriteBundle {
                     material: transient_state.default_blue.clone(),
@@ -141,5 +172,37 @@ pub fn setup_players_runonce(
         .insert(PlayerPositionDisplay { player_id: u32::from(i) })
         .insert(Debuggable::default());
     }
+    // entity counts of spawned maps
+    commands.spawn_bundle(TextBundle {
+        text: Text {
+            sections: vec![TextSection {
+                value: "".to_string(),
+                style: TextStyle {
+                    font: asset_server.load("fonts/FiraSans-Bold.ttf"),
+                    font_size: 24.0,
+                    color: Color::rgb(0.7, 0.7, 0.7),
+                    ..Default::default()
+                },
+            }],
+            ..Default::default()
+        },
+        style: Style {
+            position_type: PositionType::Absolute,
+            position: Rect {
+                top: Val::Px(5.0 + num_players as f32 * 20.0),
+                left: Val::Px(5.0),
+                ..Default::default()
+            },
+            ..Default::default()
+        },
+        visible: Visible {
+            is_transparent: true,
+            is_visible: false,
+            ..Default::default()
+        },
+        ..Default::default()
+    })
+    .insert(MapStatsDisplay)
+    .insert(Debuggable::default());
     menu_action
 }
@@ -0,0 +1,189 @@
+use std::collections::BTreeSet;
+use std::convert::TryFrom;
+
+use bevy::{prelude::*, utils::HashSet};
+
+use crate::{
+    core::{
+        dialogue::Dialogue,
+        game::Game,
+        menu::MenuAction,
+        pause::PauseAction,
+        save::{self, PlayerSave, SaveData},
+    },
+    items::Inventory,
+    loading::LoadProgress,
+    players::Player,
+    scene2d::{despawn_maps, load_next_map, map_path, MapRootFilter},
+};
+
+// A save that's being continued.  Players and dialogue are set up from it, and
+// it's cleared once the game starts.
+#[derive(Debug, Default)]
+pub struct LoadedSave(pub Option<SaveData>);
+
+// Snapshot the game so that it can be written to a save slot.
+pub fn capture_save(
+    game: &Game,
+    asset_server: &AssetServer,
+    player_query: &Query<(&Player, &Transform, &Inventory)>,
+    dialogue_query: &Query<&Dialogue>,
+) -> Option<SaveData> {
+    let mut data = SaveData::new(map_path(asset_server, &game.current_map)?);
+    data.players = player_query.iter()
+        .map(|(player, transform, inventory)| PlayerSave {
+            id: player.id,
+            position: (transform.translation.x, transform.translation.y),
+            num_gems: inventory.num_gems,
+        })
+        .collect();
+    data.collected_objects = game.collected_objects.iter().cloned().collect();
+    data.revealed_objects = game.revealed_objects.iter().cloned().collect();
+    data.dialogue_flags = capture_dialogue_flags(dialogue_query.iter());
+    data.start_dialogue_shown = game.start_dialogue_shown;
+    data.entry = game.entry.clone();
+
+    Some(data)
+}
+
+// Nodes reached in any dialogue.
+fn capture_dialogue_flags<'a>(dialogues: impl Iterator<Item = &'a Dialogue>) -> BTreeSet<String> {
+    dialogues.flat_map(|dialogue| dialogue.flags.iter().cloned()).collect()
+}
+
+// Flags that dialogue starts with, from the save being continued if there is
+// one.
+pub fn restored_dialogue_flags(loaded_save: &LoadedSave) -> HashSet<String> {
+    loaded_save.0.as_ref()
+        .map(|data| data.dialogue_flags.iter().cloned().collect())
+        .unwrap_or_default()
+}
+
+// Write the game to its save slot, reporting any errors.
+pub fn save_game(
+    game: &Game,
+    asset_server: &AssetServer,
+    player_query: &Query<(&Player, &Transform, &Inventory)>,
+    dialogue_query: &Query<&Dialogue>,
+) {
+    match capture_save(game, asset_server, player_query, dialogue_query) {
+        None => eprintln!("Warning: couldn't save since the current map has no path"),
+        Some(data) => write_save_reporting(game.save_slot, &data),
+    }
+}
+
+// Saving shouldn't interrupt the game, so errors are only reported.
+pub fn write_save_reporting(slot: u32, data: &SaveData) {
+    match save::write_save(slot, data) {
+        Ok(()) => debug!("Saved to slot {}", slot),
+        Err(err) => eprintln!("Warning: couldn't save: {:?}", err),
+    }
+}
+
+pub fn save_game_runonce(
+    In(pause_action): In<PauseAction>,
+    game: Res<Game>,
+    asset_server: Res<AssetServer>,
+    player_query: Query<(&Player, &Transform, &Inventory)>,
+    dialogue_query: Query<&Dialogue>,
+) -> PauseAction {
+    match pause_action {
+        PauseAction::Nil | PauseAction::ReturnToTitle => {}
+        PauseAction::Save => {
+            save_game(&game, &asset_server, &player_query, &dialogue_query);
+        }
+    }
+
+    pause_action
+}
+
+// Choose a save slot for a new game, or restore the game state from the last
+// save and turn it into loading its players.
+pub fn start_game_runonce(
+    In(menu_action): In<MenuAction>,
+    mut commands: Commands,
+    mut game: ResMut<Game>,
+    mut loaded_save: ResMut<LoadedSave>,
+    mut to_load: ResMut<LoadProgress>,
+    asset_server: Res<AssetServer>,
+    map_query: Query<Entity, MapRootFilter>,
+) -> MenuAction {
+    match menu_action {
+        MenuAction::Nil => MenuAction::Nil,
+        MenuAction::LoadPlayers { num_players } => {
+            game.save_slot = save::new_game_slot();
+            MenuAction::LoadPlayers { num_players }
+        }
+        MenuAction::Continue => {
+            let slot = match save::latest_save_slot() {
+                None => return MenuAction::Nil,
+                Some(slot) => slot,
+            };
+            let data = match save::read_save(slot) {
+                Ok(data) => data,
+                Err(err) => {
+                    eprintln!("Warning: couldn't load save: {:?}", err);
+                    return MenuAction::Nil;
+                }
+            };
+
+            // Respawn maps so that saved object state is applied as objects
+            // are set up.
+            despawn_maps(&mut commands, &map_query);
+            *game = Game::new(to_load.add(asset_server.load(data.current_map.as_str())));
+            game.save_slot = slot;
+            game.start_dialogue_shown = data.start_dialogue_shown;
+            game.entry = data.entry.clone();
+            game.collected_objects.extend(data.collected_objects.iter().cloned());
+            game.revealed_objects.extend(data.revealed_objects.iter().cloned());
+            load_next_map(&mut commands, &mut game);
+
+            let num_players = u8::try_from(data.players.len()).unwrap_or(u8::MAX).max(1);
+            loaded_save.0 = Some(data);
+
+            MenuAction::LoadPlayers { num_players }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::dialogue::{DialogueAsset, DialoguePlaceholder};
+
+    fn empty_asset() -> DialogueAsset {
+        DialogueAsset {
+            name: "test".to_string(),
+            nodes: Vec::new(),
+            nodes_by_name: Default::default(),
+        }
+    }
+
+    #[test]
+    fn dialogue_flags_survive_saving_and_loading() {
+        let mut dialogue = Dialogue::new(&DialoguePlaceholder::default(), empty_asset());
+        dialogue.flags.insert("intro".to_string());
+        dialogue.flags.insert("collectedBigGem".to_string());
+
+        let mut data = SaveData::new("maps/start.tmx".to_string());
+        data.dialogue_flags = capture_dialogue_flags(std::iter::once(&dialogue));
+        let contents = save::serialize_save(&data).unwrap();
+        let loaded_save = LoadedSave(Some(save::parse_save(&contents).unwrap()));
+
+        let placeholder = DialoguePlaceholder {
+            flags: restored_dialogue_flags(&loaded_save),
+            ..Default::default()
+        };
+        let restored = Dialogue::new(&placeholder, empty_asset());
+        assert_eq!(restored.flags, dialogue.flags);
+    }
+
+    #[test]
+    fn saves_from_other_versions_are_rejected() {
+        let mut data = SaveData::new("maps/start.tmx".to_string());
+        data.version = save::SAVE_VERSION - 1;
+        let contents = save::serialize_save(&data).unwrap();
+
+        assert!(save::parse_save(&contents).is_err());
+    }
+}
@@ -1,12 +1,13 @@
-use std::{marker::PhantomData, ops::Mul};
+use std::marker::PhantomData;
 
 use bevy::{
+    ecs::system::EntityCommands,
     prelude::*,
-    utils::{HashMap, HashSet},
+    utils::HashMap,
 };
-use bevy_tiled_prototype::{CreatedMapEntities, DebugConfig, LayerData, Map, MapReadyEvent, Object, TileMapChunk, TiledMapBundle, TiledMapCenter};
+use bevy_tiled_prototype::{CreatedMapEntities, DebugConfig, LayerData, Map, MapReadyEvent, Object, PropertyValue, TiledMapBundle, TiledMapCenter};
 
-use crate::{DEBUG_MODE_DEFAULT, core::{collider::{Collider, ColliderBehavior}, config::Config, dialogue::{Dialogue, DialogueAsset, DialogueEvent, DialoguePlaceholder}, game::{DialogueUiType, Game}, state::{AppState, TransientState}}, debug::Debuggable, loading::{ComplicatedLoad, LoadProgress}, motion::MoveEntityEvent, players::Player};
+use crate::{DEBUG_MODE_DEFAULT, core::{character::{Character, Direction}, map_settings::MapSettings, collider::{Collider, ColliderBehavior}, config::Config, dialogue::{Dialogue, DialogueAsset, DialogueEvent, DialoguePlaceholder}, game::{DialogueUiType, Game, ObjectId}, pause::PauseAction, state::{AppState, TransientState}}, debug::{Debuggable, MapStatsDisplay, PlayerPositionDisplay}, loading::{object_collider_shape, ComplicatedLoad, LoadProgress}, motion::MoveEntityEvent, objects::{object_kind, ObjectTypeRegistry, PrefabBehavior}, players::Player, saving::LoadedSave, transition::ScreenTransition, ui::DialogueUi};
 
 #[derive(Default)]
 pub struct MapContainer {
@@ -15,25 +16,16 @@ pub struct MapContainer {
 }
 
 pub fn initialize_levels_onboot(
-    transient_state: Res<TransientState>,
     mut commands: Commands,
     asset_server: Res<AssetServer>,
     config: Res<Config>,
     mut to_load: ResMut<LoadProgress>,
     // mut query: Query<(Entity, &Handle<Map>, &mut Visible, Option<&TileMapChunk>)>,
 ) {
-    let mut game_state = Game {
-        start_dialogue_shown: false,
-        dialogue_ui: None,
-        current_map: to_load.add(asset_server.load(config.start_map.as_path())),
-        current_dialogue: None,
-        next_map: None,
-        loaded_maps: HashSet::default(),
-        entity_visibility: HashMap::default(),
-    };
+    let mut game_state = Game::new(to_load.add(asset_server.load(config.start_map.as_path())));
 
     to_load.next_state = AppState::Menu;
-    load_next_map(&mut commands, &mut game_state, &transient_state, &config);
+    load_next_map(&mut commands, &mut game_state);
 
     commands.insert_resource(game_state);
 }
@@ -61,10 +53,181 @@ pub fn in_game_start_runonce(
     }
 }
 
+// Entities at the root of spawned maps.
+pub type MapRootFilter = (Or<(With<MapContainer>, With<Handle<Map>>)>, Without<Parent>);
+
+// Despawn every map so that they can be spawned again from scratch.  The caller
+// should reset the Game's loaded maps.
+pub fn despawn_maps(
+    commands: &mut Commands,
+    map_query: &Query<Entity, MapRootFilter>,
+) {
+    for entity in map_query.iter() {
+        commands.entity(entity).despawn_recursive();
+    }
+}
+
+// The asset path of a map, which identifies it across runs.
+pub fn map_path(asset_server: &AssetServer, map_handle: &Handle<Map>) -> Option<String> {
+    asset_server.get_handle_path(map_handle)
+        .map(|asset_path| asset_path.path().to_string_lossy().into_owned())
+}
+
+// Leave the game and go back to the title menu as if the app had just started.
+pub fn return_to_title_runonce(
+    In(pause_action): In<PauseAction>,
+    mut commands: Commands,
+    mut state: ResMut<State<AppState>>,
+    mut game_state: ResMut<Game>,
+    mut to_load: ResMut<LoadProgress>,
+    asset_server: Res<AssetServer>,
+    config: Res<Config>,
+    in_game_query: Query<Entity, InGameFilter>,
+    map_query: Query<Entity, MapRootFilter>,
+) {
+    match pause_action {
+        PauseAction::Nil | PauseAction::Save => return,
+        PauseAction::ReturnToTitle => {}
+    }
+
+    reset_to_title(&mut commands, &mut game_state, &mut to_load, &asset_server, &config, &in_game_query, &map_query);
+
+    // Replace the whole stack so that InGame isn't left underneath.
+    state.replace(AppState::Loading).expect("couldn't return to title");
+}
+
+// Entities that only exist while playing.
+pub type InGameFilter = Or<(With<Player>, With<PlayerPositionDisplay>, With<MapStatsDisplay>, With<DialogueUi>)>;
+
+// Despawn the game and start loading the title's map.  The caller should be in
+// or switch to the Loading state.
+#[allow(clippy::too_many_arguments)]
+fn reset_to_title(
+    commands: &mut Commands,
+    game_state: &mut Game,
+    to_load: &mut LoadProgress,
+    asset_server: &AssetServer,
+    config: &Config,
+    in_game_query: &Query<Entity, InGameFilter>,
+    map_query: &Query<Entity, MapRootFilter>,
+) {
+    for entity in in_game_query.iter() {
+        commands.entity(entity).despawn_recursive();
+    }
+    despawn_maps(commands, map_query);
+
+    // Maps are respawned from scratch so that collected items come back.
+    to_load.reset();
+    *game_state = Game::new(to_load.add(asset_server.load(config.start_map.as_path())));
+    to_load.next_state = AppState::Menu;
+    load_next_map(commands, game_state);
+}
+
+// Report assets that failed to load instead of waiting for them forever.  If
+// the map itself failed, players go back to the map they came from, or to the
+// title if there isn't one.
+pub fn recover_failed_load_system(
+    mut commands: Commands,
+    mut state: ResMut<State<AppState>>,
+    mut game_state: ResMut<Game>,
+    mut to_load: ResMut<LoadProgress>,
+    mut transition: ResMut<ScreenTransition>,
+    asset_server: Res<AssetServer>,
+    config: Res<Config>,
+    in_game_query: Query<Entity, InGameFilter>,
+    map_root_query: Query<Entity, MapRootFilter>,
+    map_query: Query<(Entity, Option<&MapContainer>, Option<&Handle<Map>>), MapRootFilter>,
+) {
+    let failed = to_load.failed_handles(&asset_server);
+    if failed.is_empty() {
+        return;
+    }
+    // the asset server logs why when it happens
+    for handle in failed.iter() {
+        let path = asset_server.get_handle_path(handle)
+            .map_or_else(|| format!("{:?}", handle.id), |asset_path| asset_path.path().display().to_string());
+        eprintln!("Error: couldn't load {}", path);
+        to_load.remove(handle);
+    }
+
+    // other assets are left out, but the map is needed
+    let failed_map = game_state.current_map.clone();
+    if !failed.iter().any(|handle| handle.id == failed_map.id) {
+        return;
+    }
+    for (entity, container_option, map_option) in map_query.iter() {
+        let map = container_option.map(|container| &container.asset).or(map_option);
+        if map == Some(&failed_map) {
+            commands.entity(entity).despawn_recursive();
+        }
+    }
+    game_state.loaded_maps.remove(&failed_map);
+    game_state.recent_maps.retain(|map| *map != failed_map);
+
+    let next_state = to_load.next_state;
+    to_load.reset();
+    match (next_state, game_state.recent_maps.back().cloned()) {
+        (AppState::InGame, Some(previous)) => {
+            // players were in the portal, so put them back where they
+            // arrived instead
+            game_state.current_map = previous;
+            game_state.entry = game_state.arrived_at.clone();
+            leave_loading(&mut state, AppState::InGame);
+        }
+        (AppState::InGame, None) => {
+            eprintln!("Error: no map to return to, so returning to the title");
+            reset_to_title(&mut commands, &mut game_state, &mut to_load, &asset_server, &config, &in_game_query, &map_root_query);
+        }
+        _ => leave_loading(&mut state, next_state),
+    }
+    transition.uncover();
+}
+
+// Leave the Loading state even if another state change is already queued,
+// since loading can't finish.
+fn leave_loading(state: &mut State<AppState>, next_state: AppState) {
+    if let Err(err) = state.overwrite_set(next_state) {
+        eprintln!("Warning: couldn't leave loading after a failed map: {:?}", err);
+    }
+}
+
+// Despawn maps that haven't been visited recently.  Object state is kept in
+// the Game, so they're set up again when they're visited.
+pub fn evict_maps_runonce(
+    mut commands: Commands,
+    mut game_state: ResMut<Game>,
+    config: Res<Config>,
+    map_query: Query<(Entity, Option<&MapContainer>, Option<&Handle<Map>>), MapRootFilter>,
+) {
+    let game_state = &mut *game_state;
+    // the current map always stays
+    while game_state.recent_maps.len() > config.max_loaded_maps.max(1) {
+        game_state.recent_maps.pop_front();
+    }
+    let recent_maps = &game_state.recent_maps;
+    let evicted: Vec<Handle<Map>> = game_state.loaded_maps.iter()
+        .filter(|map| !recent_maps.contains(map))
+        .cloned()
+        .collect();
+    if evicted.is_empty() {
+        return;
+    }
+    for (entity, container_option, map_option) in map_query.iter() {
+        let map = container_option.map(|container| &container.asset).or(map_option);
+        if map.map_or(false, |map| evicted.contains(map)) {
+            commands.entity(entity).despawn_recursive();
+        }
+    }
+    for map in evicted.iter() {
+        game_state.loaded_maps.remove(map);
+    }
+    debug!("Evicted {} maps", evicted.len());
+}
+
 pub fn hide_non_map_objects_runonce(
     mut commands: Commands,
     mut game_state: ResMut<Game>,
-    mut query: Query<(Entity, &mut Visible, Option<&Handle<Map>>, Option<&TileMapChunk>, Option<&Children>)>,
+    mut query: Query<(Entity, &mut Visible, Option<&Handle<Map>>, Option<&ObjectId>, Option<&Children>)>,
     // mut state: ResMut<State<AppState>>,
     // mut to_load: ResMut<LoadProgress>,
 ){
@@ -82,12 +245,13 @@ pub fn hide_non_map_objects_runonce(
         }
     }
     for &entity in non_map_entities.iter(){
-        if let Ok((_, mut visible, _, option_chunk, _)) = query.get_mut(entity) {
+        if let Ok((_, mut visible, _, option_object_id, _)) = query.get_mut(entity) {
             // chunks will always be made visible, but objects may have been hidden
-            let visible_next_load  = option_chunk.is_some() || visible.is_visible;
-            game_state
-                .entity_visibility
-                .insert(entity.clone(), visible_next_load);
+            if let Some(object_id) = option_object_id {
+                game_state
+                    .object_visibility
+                    .insert(object_id.clone(), visible.is_visible);
+            }
             commands.entity(entity).remove::<Draw>(); // for efficiency (and might help reduce textureId panick)
             visible.is_visible = false;
         }
@@ -98,49 +262,117 @@ pub fn hide_non_map_objects_runonce(
     }
 }
 
+// Where players can arrive in a map.
+#[derive(Debug)]
+pub enum ArrivalPoint {
+    Spawn,
+    // Named so that loading "map#name" arrives here.
+    Entry(String),
+}
+
+// Mark spawns and entries so that players can be moved to them.
+pub fn register_object_components(object_types: &mut ObjectTypeRegistry) {
+    object_types.add_components("spawn", insert_arrival_point);
+    object_types.add_components("entry", insert_arrival_point);
+}
+
+fn insert_arrival_point(entity_commands: &mut EntityCommands, object: &Object) {
+    match object_kind(object) {
+        ("entry", Some(name)) => { entity_commands.insert(ArrivalPoint::Entry(name.to_string())); }
+        ("entry", None) => eprintln!("Warning: entry {} needs a name after a colon", object.id),
+        _ => { entity_commands.insert(ArrivalPoint::Spawn); }
+    }
+}
+
 pub fn show_map_and_objects_runonce(
     mut commands: Commands,
-    game_state: Res<Game>,
-    mut query: Query<(Entity, &Handle<Map>, &mut Visible, Option<&Object>)>,
+    mut game_state: ResMut<Game>,
+    mut loaded_save: ResMut<LoadedSave>,
+    mut query: Query<(Entity, &Handle<Map>, &mut Visible, Option<&Object>, Option<&ArrivalPoint>, Option<&ObjectId>, Option<&Debuggable>)>,
     mut move_events: EventWriter<MoveEntityEvent<Player>>,
     // mut state: ResMut<State<AppState>>,
 ) {
+    // Players from a save were already placed where they were saved, unless
+    // it was saved on the way to this map.
+    let should_spawn = loaded_save.0.take().map_or(true, |data| data.at_spawn);
+    let entry = game_state.entry.take();
+    let mut spawn = None;
+    let mut entry_found = None;
     let mut found = false;
-    for (entity, map_owner, mut visible, object_option) in query.iter_mut() {
+    for (entity, map_owner, mut visible, object_option, arrival_option, object_id_option, debuggable_option) in query.iter_mut() {
         if *map_owner == game_state.current_map {
             found = true;
-            // objects should have been added to this hashe when processed, default to tile chunks being visible
-            // TODO: use flag to ensure object was processed or relocate default/current visibility to marker component
-            let is_visible = game_state.entity_visibility.get(&entity).unwrap_or(&true);
-
-            // for now, spawn triggers event whenever we iterate over it in the new map
-            if object_option.is_some() && object_option.unwrap().name == "spawn" {
-                move_events.send(MoveEntityEvent {
-                    object_component: PhantomData,
-                    target: entity,
-                });
+            // objects should have been added to this hash when processed, default to tile chunks being visible
+            // and debug objects (e.g. colliders from tiles) being invisible
+            let is_visible = match object_id_option {
+                Some(object_id) => *game_state.object_visibility.get(object_id).unwrap_or(&true),
+                None => debuggable_option.is_none(),
+            };
+
+            // players arrive at the named entry, or the spawn if there isn't one
+            if let (Some(object), Some(arrival_point)) = (object_option, arrival_option) {
+                match arrival_point {
+                    ArrivalPoint::Spawn => spawn = Some((entity, object_facing(object))),
+                    ArrivalPoint::Entry(name) if entry.as_deref() == Some(name.as_str()) => {
+                        entry_found = Some((entity, object_facing(object)));
+                    }
+                    ArrivalPoint::Entry(_) => {}
+                }
             }
             commands.entity(entity).insert(Draw::default());
-            visible.is_visible = *is_visible;
+            visible.is_visible = is_visible;
         }
     }
     if found {
         debug!("Show entities for current map."); // {:?}", game_state.current_map);
     }
+
+    if let (Some(name), None) = (&entry, entry_found) {
+        eprintln!("Warning: couldn't find entry {:?} in the map, so using its spawn", name);
+    }
+    game_state.arrived_at = entry.filter(|_| entry_found.is_some());
+    if should_spawn {
+        if let Some((target, direction)) = entry_found.or(spawn) {
+            move_events.send(MoveEntityEvent {
+                object_component: PhantomData,
+                target,
+                direction,
+            });
+        }
+    }
+}
+
+// Direction that arriving players face, from the object's facing property.
+fn object_facing(object: &Object) -> Option<Direction> {
+    match object.props.get("facing") {
+        Some(PropertyValue::StringValue(name)) => {
+            let direction = Direction::from_name(name);
+            if direction.is_none() {
+                eprintln!("Warning: object {} has unknown facing {:?}", object.id, name);
+            }
+            direction
+        }
+        _ => None,
+    }
 }
 
 pub fn load_next_map(
     commands: &mut Commands,
     game_state: &mut Game,
-    transient_state: &TransientState,
-    config: &Config,
 ) {
+    // the current map is the most recently visited
+    let current_map = game_state.current_map.clone();
+    game_state.recent_maps.retain(|map| *map != current_map);
+    game_state.recent_maps.push_back(current_map);
+
     // don't spawn if map already exists
     if game_state.loaded_maps.contains(&game_state.current_map) {
         return;
     }
 
-    let parent_option = Some(commands.spawn_bundle((
+    // The map itself is spawned once its asset has loaded, since its
+    // properties say how to spawn it.
+    commands.spawn_bundle((
         MapContainer {
             asset: game_state.current_map.clone(),
             ..Default::default()
@@ -150,28 +382,205 @@ pub fn load_next_map(
         GlobalTransform::default(),
         // blocks exit from loading state until spawned
         ComplicatedLoad,
-    )).id());
-
-    commands.spawn_bundle(TiledMapBundle {
-        map_asset: game_state.current_map.clone(),
-        center: TiledMapCenter(true),
-        origin: Transform {
-            translation: Vec3::new(0.0, 0.0, -100.0),
-            scale: Vec3::new(config.map_scale, config.map_scale, 1.0),
-            ..Default::default()
-        },
-        debug_config: DebugConfig {
-            enabled: DEBUG_MODE_DEFAULT,
-            material: Some(transient_state.default_blue.clone()),
-        },
-        parent_option,
-        ..Default::default()
-    });
+        PendingMapSpawn,
+    ));
     game_state
         .loaded_maps
         .insert(game_state.current_map.clone());
 }
 
+// A map container whose map hasn't been spawned yet.
+pub struct PendingMapSpawn;
+
+// A map's own copy of a material so that its tint doesn't change other maps.
+// Keeps the material it was copied from.
+pub struct TintedMaterial(pub Handle<ColorMaterial>);
+
+// A character sprite's own color, before the map's tint.
+pub struct TintedSprite(pub Color);
+
+pub fn spawn_loaded_maps_system(
+    mut commands: Commands,
+    maps: Res<Assets<Map>>,
+    transient_state: Res<TransientState>,
+    config: Res<Config>,
+    pending_query: Query<(Entity, &MapContainer), With<PendingMapSpawn>>,
+) {
+    for (entity, container) in pending_query.iter() {
+        let map = match maps.get(&container.asset) {
+            None => continue,
+            Some(map) => map,
+        };
+        let scale = MapSettings::from_map(&container.asset, map, &config).scale;
+        commands.spawn_bundle(TiledMapBundle {
+            map_asset: container.asset.clone(),
+            // centered here so that infinite maps are centered on their chunks
+            center: TiledMapCenter(false),
+            origin: centered_map_origin(map, Transform {
+                translation: Vec3::new(0.0, 0.0, -100.0),
+                scale: Vec3::new(scale, scale, 1.0),
+                ..Default::default()
+            }),
+            debug_config: DebugConfig {
+                enabled: DEBUG_MODE_DEFAULT,
+                material: Some(transient_state.default_blue.clone()),
+            },
+            parent_option: Some(entity),
+            ..Default::default()
+        });
+        commands.entity(entity).remove::<PendingMapSpawn>();
+    }
+}
+
+// Read the current map's properties once it has loaded, and start loading its
+// dialogue so that it's ready when the map is shown.
+pub fn update_map_settings_system(
+    mut game_state: ResMut<Game>,
+    mut to_load: ResMut<LoadProgress>,
+    asset_server: Res<AssetServer>,
+    maps: Res<Assets<Map>>,
+    config: Res<Config>,
+) {
+    if game_state.map_settings.as_ref().map(|settings| &settings.map) == Some(&game_state.current_map) {
+        return;
+    }
+    let map = match maps.get(&game_state.current_map) {
+        None => return,
+        Some(map) => map,
+    };
+    let settings = MapSettings::from_map(&game_state.current_map, map, &config);
+    game_state.map_dialogue = Some(to_load.add(asset_server.load(config.localized_path(&settings.dialogue).as_path())));
+    game_state.map_settings = Some(settings);
+}
+
+// Apply the current map's background and tint.  Its camera mode and music are
+// used by the camera and music systems.
+pub fn apply_map_settings_runonce(
+    mut commands: Commands,
+    game_state: Res<Game>,
+    mut clear_color: ResMut<ClearColor>,
+    mut materials: ResMut<Assets<ColorMaterial>>,
+    mut material_query: Query<(Entity, &Handle<Map>, &mut Handle<ColorMaterial>, Option<&TintedMaterial>)>,
+    mut character_query: Query<(Entity, &mut TextureAtlasSprite, Option<&TintedSprite>), With<Character>>,
+) {
+    let settings = match &game_state.map_settings {
+        None => return,
+        Some(settings) => settings,
+    };
+    clear_color.0 = settings.background;
+
+    // Tilesets' materials can be shared with other maps, so the map gets its
+    // own copies to tint.
+    let mut copies: HashMap<Handle<ColorMaterial>, Handle<ColorMaterial>> = Default::default();
+    for (entity, map, mut material_handle, tinted_option) in material_query.iter_mut() {
+        if *map != game_state.current_map {
+            continue;
+        }
+        if let Some(TintedMaterial(original)) = tinted_option {
+            let color = match materials.get(original) {
+                None => continue,
+                Some(material) => tint_color(material.color, settings.tint),
+            };
+            if let Some(material) = materials.get_mut(&*material_handle) {
+                material.color = color;
+            }
+            continue;
+        }
+        if settings.tint == Color::WHITE {
+            continue;
+        }
+        let original = material_handle.clone();
+        let copy = match copies.get(&original) {
+            Some(copy) => copy.clone(),
+            None => {
+                let mut material = match materials.get(&original) {
+                    None => continue,
+                    Some(material) => material.clone(),
+                };
+                material.color = tint_color(material.color, settings.tint);
+                let copy = materials.add(material);
+                copies.insert(original.clone(), copy.clone());
+                copy
+            }
+        };
+        *material_handle = copy;
+        commands.entity(entity).insert(TintedMaterial(original));
+    }
+    for (entity, mut sprite, tinted_option) in character_query.iter_mut() {
+        let base = match tinted_option {
+            Some(TintedSprite(base)) => *base,
+            None => {
+                commands.entity(entity).insert(TintedSprite(sprite.color));
+                sprite.color
+            }
+        };
+        sprite.color = tint_color(base, settings.tint);
+    }
+}
+
+// The color multiplied by the tint.
+fn tint_color(color: Color, tint: Color) -> Color {
+    Color::rgba(
+        color.r() * tint.r(),
+        color.g() * tint.g(),
+        color.b() * tint.b(),
+        color.a() * tint.a(),
+    )
+}
+
+// The map and entry of a portal's "map#entry" destination.
+pub fn split_destination(destination: &str) -> (&str, Option<String>) {
+    match destination.find('#') {
+        None => (destination, None),
+        Some(index) => (&destination[..index], Some(destination[index + 1..].to_string())),
+    }
+}
+
+// Asset path of a map named by a portal.  All maps need to be in maps/ for now.
+pub fn destination_map_path(map_name: &str) -> String {
+    format!("maps/{}.tmx", map_name)
+}
+
+// Start loading the maps that the current map's portals lead to.  The asset
+// server loads their tilesets along with them, and the handles in the Game
+// keep them around until the players move on.
+pub fn preload_adjacent_maps_system(
+    mut game_state: ResMut<Game>,
+    asset_server: Res<AssetServer>,
+    maps: Res<Assets<Map>>,
+    object_types: Res<ObjectTypeRegistry>,
+) {
+    if game_state.next_maps_from.as_ref() == Some(&game_state.current_map) {
+        return;
+    }
+    // wait until the current map itself is loaded
+    let map = match maps.get(&game_state.current_map) {
+        None => return,
+        Some(map) => map,
+    };
+
+    let mut next_maps: HashMap<String, Handle<Map>> = Default::default();
+    for group in map.map.object_groups.iter() {
+        for tiled_object in group.objects.iter() {
+            let object = Object::new(tiled_object);
+            let destination = match object_types.prefab(&object) {
+                Some((prefab, Some(argument))) if prefab.behaviors.iter()
+                    .any(|behavior| matches!(behavior, PrefabBehavior::Load)) => argument,
+                _ => continue,
+            };
+            let (map_name, _) = split_destination(destination);
+            let path = destination_map_path(map_name);
+            if !next_maps.contains_key(&path) {
+                debug!("Preloading {}", path);
+                let handle = asset_server.load(path.as_str());
+                next_maps.insert(path, handle);
+            }
+        }
+    }
+    // maps no longer adjacent are dropped, unless spawned
+    game_state.next_maps = next_maps;
+    game_state.next_maps_from = Some(game_state.current_map.clone());
+}
 
 // until bevy_tiled has better support for this, we have to reach in and create objects based on tiles
 // TODO: tile objects set up to clear on reload, but MapReadyEvent only runs first load, so they are not re-created
@@ -181,7 +590,6 @@ pub fn create_tile_objects_system(
     query: Query<&MapContainer>,
     maps: Res<Assets<Map>>,
     transient_state: Res<TransientState>,
-    mut game_state: ResMut<Game>,
     mut map_query: Query<(&mut CreatedMapEntities, &Handle<Map>)>,
     config: Res<Config>,
 ) {
@@ -189,71 +597,182 @@ pub fn create_tile_objects_system(
         let map_entity = event.map_entity_option.expect("why didn't you give this map an entity?");
         if let Ok(container) = query.get(map_entity) {
             maps.get(container.asset.clone()).map(|map| {
-                let mut templates: HashMap<u32, Object> = Default::default();
+                let scale = MapSettings::from_map(&container.asset, map, &config).scale;
+                // collision shapes of each tile, with their offsets within the tile
+                let mut templates: HashMap<u32, Vec<(Vec2, Object)>> = Default::default();
                 // find all tiles with object layers
                 for tileset in map.map.tilesets.iter() {
                     for tile in tileset.tiles.iter() {
                         if let Some(group) = &tile.objectgroup {
+                            let shapes = templates.entry(tileset.first_gid + tile.id).or_insert_with(Vec::new);
                             for obj in &group.objects {
-                                templates.insert(tileset.first_gid + tile.id, Object::new(&obj));
+                                let template = Object::new(&obj);
+                                shapes.push((template.position, template));
                             }
                         }
                     }
                 }
                 // go through visibile layers for this map and add obstruction objects for tiles
-                // NOTE: for now this assumes the entire tile obstructs
                 for layer in map.map.layers.iter() {
                     if !layer.visible { continue; }
-                    if let LayerData::Finite(tiles) = &layer.tiles {
-                        for (tile_y, tilerow) in tiles.iter().enumerate() {
-                            for (tile_x, tile) in tilerow.iter().enumerate() {
-                                templates.get_mut(&tile.gid).map(|obj| {
-                                    obj.position.x = tile_x as f32 * map.tile_size.x;
-                                    obj.position.y = tile_y as f32 * map.tile_size.y;
-                                    obj.visible = false;
-                                    let mut entity_commands = obj.spawn(
-                                        &mut commands, None,
-                                        &map.map,
-                                        container.asset.clone(),
-                                        &map.center(
-                                        Transform {
-                                            translation: Vec3::new(0.0, 0.0, -100.0),
-                                            scale: Vec3::new(config.map_scale, config.map_scale, 1.0),
-                                            ..Default::default()
-                                        }),
-                                        &bevy_tiled_prototype::DebugConfig {
-                                            enabled: false,
-                                            material: Some(transient_state.default_blue.clone()),
-                                        }
-                                    );
-                                    entity_commands
-                                        .insert( // for now assume objects in tiles mean entire tile obstructs
-                                            Collider::single(
-                                                ColliderBehavior::Obstruct,
-                                                map.tile_size.clone().mul(config.map_scale),
-                                                Vec2::new(0.0, 0.0)
-                                            )
-                                        )
-                                        .insert(Debuggable::default());
-                                    // make sure these objects are cleared on auto-reload
-                                    for (mut created_map_entities, map_handle) in map_query.iter_mut(){
-                                        if container.asset == *map_handle {
-                                            for ((_layer_id, _tileset_guid), vec_entities) in created_map_entities.created_layer_entities.iter_mut() {
-                                                vec_entities.push(entity_commands.id().clone());
-                                                break;  // only need to do this for any one layer in this map (workaround)
-                                            }
-                                        }
+                    for (tile_x, tile_y, gid) in layer_tiles(&layer.tiles) {
+                        let shapes = match templates.get_mut(&gid) {
+                            None => continue,
+                            Some(shapes) => shapes,
+                        };
+                        for (offset, obj) in shapes.iter_mut() {
+                            obj.position.x = tile_x as f32 * map.tile_size.x + offset.x;
+                            obj.position.y = tile_y as f32 * map.tile_size.y + offset.y;
+                            obj.visible = false;
+                            let mut entity_commands = obj.spawn(
+                                &mut commands, None,
+                                &map.map,
+                                container.asset.clone(),
+                                &centered_map_origin(map,
+                                Transform {
+                                    translation: Vec3::new(0.0, 0.0, -100.0),
+                                    scale: Vec3::new(scale, scale, 1.0),
+                                    ..Default::default()
+                                }),
+                                &bevy_tiled_prototype::DebugConfig {
+                                    enabled: false,
+                                    material: Some(transient_state.default_blue.clone()),
+                                }
+                            );
+                            // shapes drawn in the tile's collision editor obstruct
+                            entity_commands
+                                .insert(
+                                    Collider::with_shape(
+                                        [ColliderBehavior::Obstruct].iter().cloned().collect(),
+                                        object_collider_shape(obj),
+                                        Vec2::new(0.0, 0.0)
+                                    )
+                                )
+                                .insert(Debuggable::default());
+                            // make sure these objects are cleared on auto-reload
+                            for (mut created_map_entities, map_handle) in map_query.iter_mut(){
+                                if container.asset == *map_handle {
+                                    for ((_layer_id, _tileset_guid), vec_entities) in created_map_entities.created_layer_entities.iter_mut() {
+                                        vec_entities.push(entity_commands.id().clone());
+                                        break;  // only need to do this for any one layer in this map (workaround)
                                     }
-                                    // debug objects are invisible by default
-                                    game_state.entity_visibility.insert(entity_commands.id(),false);
-                                });
+                                }
                             }
                         }
-                    } else {
-                        panic!("Infinte maps not supported")
                     }
                 }
             });
         }
     }
 }
+
+// Transform that centers a map on the origin.  The width and height of an
+// infinite map only cover part of it, so it's centered on its chunks instead.
+fn centered_map_origin(map: &Map, origin: Transform) -> Transform {
+    let bounds = map.map.layers.iter()
+        .filter_map(|layer| layer_bounds(&layer.tiles))
+        .fold(None, |bounds: Option<((i32, i32), (i32, i32))>, (min, max)| Some(match bounds {
+            None => (min, max),
+            Some((bounds_min, bounds_max)) => (
+                (bounds_min.0.min(min.0), bounds_min.1.min(min.1)),
+                (bounds_max.0.max(max.0), bounds_max.1.max(max.1)),
+            ),
+        }));
+    let (min, max) = match bounds {
+        None => return map.center(origin),
+        Some(bounds) => bounds,
+    };
+    // tiles go down the screen as y increases
+    let center = Vec2::new(
+        (min.0 + max.0) as f32 / 2.0 * map.tile_size.x,
+        -(min.1 + max.1) as f32 / 2.0 * map.tile_size.y,
+    );
+
+    Transform::from_matrix(origin.compute_matrix() * Mat4::from_translation(-center.extend(0.0)))
+}
+
+// Tile coordinates of the corners of an infinite layer's chunks, with the
+// maximum exclusive.  Finite layers are covered by the map's size instead.
+fn layer_bounds(data: &LayerData) -> Option<((i32, i32), (i32, i32))> {
+    match data {
+        LayerData::Finite(_) => None,
+        LayerData::Infinite(chunks) => {
+            let min = (
+                chunks.values().map(|chunk| chunk.x).min()?,
+                chunks.values().map(|chunk| chunk.y).min()?,
+            );
+            let max = (
+                chunks.values().map(|chunk| chunk.x + chunk.width as i32).max()?,
+                chunks.values().map(|chunk| chunk.y + chunk.height as i32).max()?,
+            );
+            Some((min, max))
+        }
+    }
+}
+
+// Coordinates and global IDs of the tiles in a layer.  Chunks of infinite maps
+// can be at negative coordinates.
+fn layer_tiles(data: &LayerData) -> Vec<(i32, i32, u32)> {
+    let mut tiles = Vec::new();
+    match data {
+        LayerData::Finite(rows) => {
+            for (tile_y, row) in rows.iter().enumerate() {
+                for (tile_x, tile) in row.iter().enumerate() {
+                    tiles.push((tile_x as i32, tile_y as i32, tile.gid));
+                }
+            }
+        }
+        LayerData::Infinite(chunks) => {
+            for chunk in chunks.values() {
+                for (y, row) in chunk.tiles.iter().enumerate() {
+                    for (x, tile) in row.iter().enumerate() {
+                        tiles.push((chunk.x + x as i32, chunk.y + y as i32, tile.gid));
+                    }
+                }
+            }
+        }
+    }
+
+    tiles
+}
+
+#[cfg(test)]
+mod tests {
+    use tiled::{Chunk, LayerTile};
+
+    use super::*;
+
+    fn chunk(x: i32, y: i32, gids: &[[u32; 2]; 2]) -> ((i32, i32), Chunk) {
+        let tiles = gids.iter()
+            .map(|row| row.iter().map(|&gid| LayerTile::new(gid)).collect())
+            .collect();
+
+        ((x, y), Chunk { x, y, width: 2, height: 2, tiles })
+    }
+
+    #[test]
+    fn infinite_layer_tiles_at_negative_coordinates() {
+        let data = LayerData::Infinite(vec![
+            chunk(-2, -2, &[[1, 0], [0, 2]]),
+            chunk(4, 0, &[[0, 3], [0, 0]]),
+        ].into_iter().collect());
+
+        let mut tiles: Vec<(i32, i32, u32)> = layer_tiles(&data).into_iter()
+            .filter(|(_, _, gid)| *gid != 0)
+            .collect();
+        tiles.sort();
+        assert_eq!(tiles, vec![(-2, -2, 1), (-1, -1, 2), (5, 0, 3)]);
+        assert_eq!(layer_bounds(&data), Some(((-2, -2), (6, 2))));
+        assert_eq!(layer_bounds(&LayerData::Finite(Vec::new())), None);
+    }
+
+    #[test]
+    fn split_portal_destinations() {
+        assert_eq!(split_destination("ritualspace"), ("ritualspace", None));
+        assert_eq!(split_destination("ritualspace#door"), ("ritualspace", Some("door".to_string())));
+        // only the first # separates the entry
+        assert_eq!(split_destination("ritualspace#a#b"), ("ritualspace", Some("a#b".to_string())));
+        assert_eq!(split_destination("#door"), ("", Some("door".to_string())));
+        assert_eq!(destination_map_path("ritualspace"), "maps/ritualspace.tmx");
+    }
+}
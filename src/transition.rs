@@ -0,0 +1,327 @@
+use bevy::prelude::*;
+
+use crate::core::{character::Direction, state::AppState};
+
+// Seconds to cover or uncover the screen.
+const TRANSITION_TIME: f32 = 0.4;
+
+#[derive(Debug, Default)]
+pub struct TransitionPlugin;
+
+impl Plugin for TransitionPlugin {
+    fn build(&self, app: &mut AppBuilder) {
+        app
+            .insert_resource(ScreenTransition::default())
+            .add_startup_system(setup_transition_overlay_onboot.system())
+            .add_system(screen_transition_system.system())
+            .add_system_set(SystemSet::on_enter(AppState::InGame)
+                .with_system(uncover_screen_runonce.system()));
+    }
+}
+
+// How the screen is covered while changing maps.  Portals choose one with
+// their "transition" property, e.g. "fade", "fade:white", "iris" or
+// "slide:left".
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TransitionKind {
+    // Fade to a color and back.
+    Fade(Color),
+    // A circle closing on the middle of the screen and opening again.
+    Iris,
+    // A panel sliding across the screen in a direction.
+    Slide(Direction),
+}
+
+impl Default for TransitionKind {
+    fn default() -> Self {
+        TransitionKind::Fade(Color::BLACK)
+    }
+}
+
+impl TransitionKind {
+    pub fn parse(s: &str) -> Option<TransitionKind> {
+        let (name, argument) = match s.find(':') {
+            None => (s, None),
+            Some(index) => (&s[..index], Some(&s[index + 1..])),
+        };
+        match name.trim().to_lowercase().as_str() {
+            "fade" => match argument {
+                None => Some(TransitionKind::Fade(Color::BLACK)),
+                Some(color) => parse_color(color).map(TransitionKind::Fade),
+            },
+            "iris" => Some(TransitionKind::Iris),
+            "slide" => match argument {
+                None => Some(TransitionKind::Slide(Direction::West)),
+                Some(direction) => Direction::from_name(direction.trim()).map(TransitionKind::Slide),
+            },
+            _ => None,
+        }
+    }
+}
+
+fn parse_color(s: &str) -> Option<Color> {
+    match s.trim().to_lowercase().as_str() {
+        "black" => Some(Color::BLACK),
+        "white" => Some(Color::WHITE),
+        other => Color::hex(other.trim_start_matches('#')).ok(),
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TransitionPhase {
+    Idle,
+    Covering,
+    // Waiting for the map to load.
+    Covered,
+    Uncovering,
+}
+
+// The transition around a map change.  Player input is ignored while it's
+// active.
+#[derive(Debug)]
+pub struct ScreenTransition {
+    kind: TransitionKind,
+    phase: TransitionPhase,
+    elapsed: f32,
+    // Where the players are going once the screen is covered.
+    destination: Option<String>,
+}
+
+impl Default for ScreenTransition {
+    fn default() -> Self {
+        ScreenTransition {
+            kind: TransitionKind::default(),
+            phase: TransitionPhase::Idle,
+            elapsed: 0.0,
+            destination: None,
+        }
+    }
+}
+
+impl ScreenTransition {
+    pub fn is_active(&self) -> bool {
+        self.phase != TransitionPhase::Idle
+    }
+
+    // Start covering the screen.  Does nothing if a transition is already
+    // running.
+    pub fn begin(&mut self, kind: TransitionKind, destination: String) {
+        if self.is_active() {
+            return;
+        }
+        self.kind = kind;
+        self.phase = TransitionPhase::Covering;
+        self.elapsed = 0.0;
+        self.destination = Some(destination);
+    }
+
+    // The destination, once the screen is fully covered.
+    pub fn take_destination(&mut self) -> Option<String> {
+        match self.phase {
+            TransitionPhase::Covered => self.destination.take(),
+            _ => None,
+        }
+    }
+
+    pub fn uncover(&mut self) {
+        if self.phase == TransitionPhase::Covered {
+            self.phase = TransitionPhase::Uncovering;
+            self.elapsed = 0.0;
+        }
+    }
+
+    // How much of the screen is covered, from 0 to 1.
+    fn coverage(&self) -> f32 {
+        let t = (self.elapsed / TRANSITION_TIME).min(1.0);
+        match self.phase {
+            TransitionPhase::Idle => 0.0,
+            TransitionPhase::Covering => t,
+            TransitionPhase::Covered => 1.0,
+            TransitionPhase::Uncovering => 1.0 - t,
+        }
+    }
+}
+
+// Parts of the overlay drawn over the game.
+pub enum TransitionOverlay {
+    // Used for fades and slides.
+    Panel,
+    // The ring around the iris hole.
+    IrisHole,
+    // Fills the screen around the hole.
+    IrisSide(usize),
+}
+
+struct OverlayMaterials {
+    panel: Handle<ColorMaterial>,
+}
+
+fn full_screen_style() -> Style {
+    Style {
+        position_type: PositionType::Absolute,
+        position: Rect {
+            left: Val::Px(0.0),
+            top: Val::Px(0.0),
+            ..Default::default()
+        },
+        size: Size::new(Val::Percent(100.0), Val::Percent(100.0)),
+        ..Default::default()
+    }
+}
+
+fn hidden() -> Visible {
+    Visible {
+        is_transparent: true,
+        is_visible: false,
+    }
+}
+
+fn setup_transition_overlay_onboot(
+    mut commands: Commands,
+    asset_server: Res<AssetServer>,
+    mut materials: ResMut<Assets<ColorMaterial>>,
+) {
+    let panel = materials.add(Color::BLACK.into());
+    let black = materials.add(Color::BLACK.into());
+    let iris = materials.add(ColorMaterial::texture(asset_server.load("sprites/iris.png")));
+
+    commands.spawn_bundle(NodeBundle {
+        style: full_screen_style(),
+        material: materials.add(Color::NONE.into()),
+        visible: hidden(),
+        ..Default::default()
+    })
+    .with_children(|parent| {
+        parent.spawn_bundle(NodeBundle {
+            style: full_screen_style(),
+            material: panel.clone(),
+            visible: hidden(),
+            ..Default::default()
+        })
+        .insert(TransitionOverlay::Panel);
+        parent.spawn_bundle(NodeBundle {
+            style: full_screen_style(),
+            material: iris,
+            visible: hidden(),
+            ..Default::default()
+        })
+        .insert(TransitionOverlay::IrisHole);
+        for side in 0..4 {
+            parent.spawn_bundle(NodeBundle {
+                style: full_screen_style(),
+                material: black.clone(),
+                visible: hidden(),
+                ..Default::default()
+            })
+            .insert(TransitionOverlay::IrisSide(side));
+        }
+    });
+
+    commands.insert_resource(OverlayMaterials { panel });
+}
+
+fn screen_transition_system(
+    time: Res<Time>,
+    windows: Res<Windows>,
+    mut transition: ResMut<ScreenTransition>,
+    overlay_materials: Res<OverlayMaterials>,
+    mut materials: ResMut<Assets<ColorMaterial>>,
+    mut overlay_query: Query<(&TransitionOverlay, &mut Style, &mut Visible)>,
+) {
+    match transition.phase {
+        TransitionPhase::Covering | TransitionPhase::Uncovering => {
+            transition.elapsed += time.delta_seconds();
+            if transition.elapsed >= TRANSITION_TIME {
+                transition.phase = match transition.phase {
+                    TransitionPhase::Covering => TransitionPhase::Covered,
+                    _ => TransitionPhase::Idle,
+                };
+            }
+        }
+        TransitionPhase::Idle | TransitionPhase::Covered => {}
+    }
+
+    let coverage = transition.coverage();
+    let active = transition.is_active();
+    let (width, height) = windows.get_primary()
+        .map_or((0.0, 0.0), |window| (window.width(), window.height()));
+
+    if let TransitionKind::Fade(color) = transition.kind {
+        if let Some(material) = materials.get_mut(&overlay_materials.panel) {
+            material.color = color;
+            material.color.set_a(color.a() * coverage);
+        }
+    } else if let Some(material) = materials.get_mut(&overlay_materials.panel) {
+        material.color = Color::BLACK;
+    }
+
+    // radius of the iris hole, fully open when it reaches the corners
+    let radius = (1.0 - coverage) * (width * width + height * height).sqrt() / 2.0;
+    let (center_x, center_y) = (width / 2.0, height / 2.0);
+
+    for (overlay, mut style, mut visible) in overlay_query.iter_mut() {
+        match overlay {
+            TransitionOverlay::Panel => {
+                visible.is_visible = active && matches!(transition.kind, TransitionKind::Fade(_) | TransitionKind::Slide(_));
+                let offset = match transition.kind {
+                    // comes in from behind the direction and leaves ahead of it
+                    TransitionKind::Slide(direction) => match transition.phase {
+                        TransitionPhase::Uncovering => direction.to_vec2() * (1.0 - coverage),
+                        _ => -direction.to_vec2() * (1.0 - coverage),
+                    },
+                    TransitionKind::Fade(_) | TransitionKind::Iris => Vec2::ZERO,
+                };
+                style.position.left = Val::Percent(offset.x * 100.0);
+                style.position.top = Val::Percent(-offset.y * 100.0);
+            }
+            TransitionOverlay::IrisHole => {
+                visible.is_visible = active && transition.kind == TransitionKind::Iris;
+                style.position.left = Val::Px(center_x - radius);
+                style.position.top = Val::Px(center_y - radius);
+                style.size = Size::new(Val::Px(radius * 2.0), Val::Px(radius * 2.0));
+            }
+            TransitionOverlay::IrisSide(side) => {
+                visible.is_visible = active && transition.kind == TransitionKind::Iris;
+                // top, bottom, left and right of the hole
+                let (left, top, w, h) = match side {
+                    0 => (0.0, 0.0, width, center_y - radius),
+                    1 => (0.0, center_y + radius, width, height - center_y - radius),
+                    2 => (0.0, center_y - radius, center_x - radius, radius * 2.0),
+                    _ => (center_x + radius, center_y - radius, width - center_x - radius, radius * 2.0),
+                };
+                style.position.left = Val::Px(left);
+                style.position.top = Val::Px(top);
+                style.size = Size::new(Val::Px(w.max(0.0)), Val::Px(h.max(0.0)));
+            }
+        }
+    }
+}
+
+// The new map is shown, so reveal it.
+fn uncover_screen_runonce(mut transition: ResMut<ScreenTransition>) {
+    transition.uncover();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_transition_kinds() {
+        assert_eq!(TransitionKind::parse("fade"), Some(TransitionKind::Fade(Color::BLACK)));
+        assert_eq!(TransitionKind::parse("fade:white"), Some(TransitionKind::Fade(Color::WHITE)));
+        assert_eq!(TransitionKind::parse("Fade: #ff0000"), Some(TransitionKind::Fade(Color::rgb(1.0, 0.0, 0.0))));
+        assert_eq!(TransitionKind::parse("iris"), Some(TransitionKind::Iris));
+        assert_eq!(TransitionKind::parse("slide"), Some(TransitionKind::Slide(Direction::West)));
+        assert_eq!(TransitionKind::parse("slide:up"), Some(TransitionKind::Slide(Direction::North)));
+        assert_eq!(TransitionKind::parse("slide: East"), Some(TransitionKind::Slide(Direction::East)));
+    }
+
+    #[test]
+    fn parse_unknown_transitions() {
+        assert_eq!(TransitionKind::parse(""), None);
+        assert_eq!(TransitionKind::parse("wipe"), None);
+        assert_eq!(TransitionKind::parse("fade:plaid"), None);
+        assert_eq!(TransitionKind::parse("slide:sideways"), None);
+    }
+}
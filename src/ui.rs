@@ -3,32 +3,49 @@ use crate::{
     AppState,
     core::{
         config::Config,
-        dialogue::{Dialogue, DialogueEvent, DialoguePlaceholder},
+        dialogue::{Dialogue, DialogueAsset, DialogueEvent, DialoguePlaceholder},
         game::Game,
         menu::MenuAction
     },
     loading::LoadProgress,
+    saving::{restored_dialogue_flags, LoadedSave},
 };
 
 // The UI element that displays dialogue.
 pub struct DialogueWindow;
 
+// Root of the in-game UI so that it can be despawned when leaving the game.
+pub struct DialogueUi;
+
+// Text that's revealed a few characters at a time.
+#[derive(Debug, Default)]
+pub struct RevealedText {
+    pub full_text: String,
+    // Number of characters revealed so far.  Fractional so that slow speeds
+    // still make progress each frame.
+    pub num_revealed: f32,
+}
+
 pub fn display_dialogue_system(
     mut event_reader: EventReader<DialogueEvent>,
-    mut text_query: Query<&mut Text, With<Dialogue>>,
+    mut text_query: Query<(&mut Text, &mut RevealedText), With<Dialogue>>,
     mut visible_query: Query<&mut Visible, With<DialogueWindow>>,
 ) {
     for event in event_reader.iter() {
-        for mut ui_text in text_query.iter_mut() {
+        for (mut ui_text, mut revealed) in text_query.iter_mut() {
             match event {
+                DialogueEvent::Begin(_) => {}
                 DialogueEvent::End => {
                     ui_text.sections[0].value = "".to_string();
+                    revealed.full_text.clear();
                     for mut visible in visible_query.iter_mut() {
                         visible.is_visible = false;
                     }
                 }
                 DialogueEvent::Text(text) => {
-                    ui_text.sections[0].value = text.clone();
+                    ui_text.sections[0].value = "".to_string();
+                    revealed.full_text = text.clone();
+                    revealed.num_revealed = 0.0;
                     for mut visible in visible_query.iter_mut() {
                         visible.is_visible = true;
                     }
@@ -38,6 +55,66 @@ pub fn display_dialogue_system(
     }
 }
 
+// Reveal dialogue text at the speed set in the user's settings.
+pub fn reveal_dialogue_text_system(
+    time: Res<Time>,
+    config: Res<Config>,
+    mut text_query: Query<(&mut Text, &mut RevealedText)>,
+) {
+    for (mut ui_text, mut revealed) in text_query.iter_mut() {
+        let num_chars = revealed.full_text.chars().count();
+        if revealed.num_revealed >= num_chars as f32 {
+            continue;
+        }
+        let text_speed = config.settings.text_speed;
+        revealed.num_revealed = if text_speed <= 0.0 {
+            num_chars as f32
+        } else {
+            revealed.num_revealed + text_speed * time.delta_seconds()
+        };
+        let num_shown = (revealed.num_revealed as usize).min(num_chars);
+        ui_text.sections[0].value = revealed.full_text.chars().take(num_shown).collect();
+    }
+}
+
+// Switch dialogue to the language chosen in the settings as soon as it
+// changes.  Dialogue in progress ends since its text is in the old language.
+pub fn localize_dialogue_system(
+    config: Res<Config>,
+    asset_server: Res<AssetServer>,
+    dialogue_assets: Res<Assets<DialogueAsset>>,
+    mut game_state: ResMut<Game>,
+    mut language: Local<Option<String>>,
+    mut pending: Local<Option<Handle<DialogueAsset>>>,
+    mut dialogue_query: Query<&mut Dialogue>,
+    mut dialogue_events: EventWriter<DialogueEvent>,
+) {
+    let current_language = &config.settings.language;
+    if language.as_ref() != Some(current_language) {
+        // the first run only remembers the language dialogue was loaded in
+        if language.is_some() {
+            let path = game_state.map_settings.as_ref()
+                .map_or(&config.start_dialogue, |settings| &settings.dialogue);
+            let handle: Handle<DialogueAsset> = asset_server.load(config.localized_path(path).as_path());
+            game_state.map_dialogue = Some(handle.clone());
+            *pending = Some(handle);
+        }
+        *language = Some(current_language.clone());
+    }
+
+    let asset = match pending.as_ref().and_then(|handle| dialogue_assets.get(handle)) {
+        None => return,
+        Some(asset) => asset,
+    };
+    let handle = pending.take().unwrap();
+    for mut dialogue in dialogue_query.iter_mut() {
+        if dialogue.in_progress() {
+            dialogue_events.send(DialogueEvent::End);
+        }
+        dialogue.replace_asset(handle.clone(), asset.clone());
+    }
+}
+
 pub fn setup_dialogue_window_runonce (
     In(menu_action): In<MenuAction>,
     mut commands: Commands,
@@ -47,10 +124,11 @@ pub fn setup_dialogue_window_runonce (
     config: Res<Config>,
     mut to_load: ResMut<LoadProgress>,
     mut materials: ResMut<Assets<ColorMaterial>>,
+    loaded_save: Res<LoadedSave>,
 ) {
     // todo: dialogue for each player
     let _num_players = match menu_action {
-        MenuAction::Nil => return,
+        MenuAction::Nil | MenuAction::Continue => return,
         MenuAction::LoadPlayers { num_players } => num_players,
     };
 
@@ -58,7 +136,10 @@ pub fn setup_dialogue_window_runonce (
     to_load.next_state = AppState::InGame;
 
     // Load dialogue.
-    let level_dialogue = to_load.add(asset_server.load(config.start_dialogue.as_path()));
+    let level_dialogue = match &game_state.map_dialogue {
+        Some(handle) => to_load.add(handle.clone()),
+        None => to_load.add(asset_server.load(config.localized_path(&config.start_dialogue).as_path())),
+    };
     // Root node.
     commands.spawn_bundle(NodeBundle {
         style: Style {
@@ -74,6 +155,7 @@ pub fn setup_dialogue_window_runonce (
         material: materials.add(Color::NONE.into()),
         ..Default::default()
     })
+    .insert(DialogueUi {})
     .with_children(|parent| {
         // Dialogue window.
         parent.spawn_bundle(NodeBundle {
@@ -114,8 +196,10 @@ pub fn setup_dialogue_window_runonce (
             })
             .insert(DialoguePlaceholder {
                 handle: level_dialogue,
+                flags: restored_dialogue_flags(&loaded_save),
                 ..Default::default()
             })
+            .insert(RevealedText::default())
             .id();
             // end: let dialogue = ...
 